@@ -0,0 +1,114 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Commands for the motion task. Any input source (endswitch, serial, ...)
+// sends them here and the filament changer executes them one at a time.
+
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal,
+};
+
+use crate::{
+    config::{Backlash, SelectorPark},
+    lane_info::LaneInfoUpdate,
+    material::Material,
+    toolchange::ToolchangeKind,
+    units::Millimeters,
+};
+
+const COMMAND_QUEUE_SIZE: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Home,
+    Select(usize),
+    // Moves the selector to a position in mm from home.
+    MoveSelector(Millimeters),
+    // Declares a lane parked after its filament was cleared by hand.
+    MarkParked(usize),
+    // Changes the material, and with it the profile, of a lane.
+    SetMaterial(usize, Material),
+    // Changes what else is recorded about the filament in a lane.
+    UpdateLaneInfo(usize, LaneInfoUpdate),
+    // Switches between cutting and tip forming.
+    SetToolchange(ToolchangeKind),
+    // Changes how the selector takes up backlash.
+    SetBacklash(Backlash),
+    // Changes where the selector waits while this lane is loaded.
+    SetSelectorPark(usize, SelectorPark),
+    // Measures the bowden lengths of a lane with the filament sensors.
+    CalibrateBowden(usize),
+    // Moves the selector to a lane to adjust its position.
+    CalibrateSelector(usize),
+    // Moves the selector by signed steps while adjusting a lane.
+    JogSelector(i32),
+    // Stores the adjusted position for the lane.
+    FinishSelectorCalibration,
+    // Feeds a lane a known length to calibrate the extruder steps/mm.
+    CalibrateExtruder(usize),
+    // The length the user measured was actually fed.
+    FinishExtruderCalibration(Millimeters),
+}
+
+static COMMANDS: Channel<CriticalSectionRawMutex, Command, COMMAND_QUEUE_SIZE> = Channel::new();
+
+// Abort bypasses the queue: it has to reach the motion task while it is
+// still busy with the previous command.
+static ABORT: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub async fn send(command: Command) {
+    log::debug!("Command: {:?}", command);
+    COMMANDS.send(command).await;
+}
+
+pub async fn receive() -> Command {
+    COMMANDS.receive().await
+}
+
+// Stops whatever the motion task is doing and drops the queued commands.
+pub fn abort() {
+    log::info!("Abort requested");
+    while COMMANDS.try_receive().is_ok() {}
+    ABORT.signal(());
+}
+
+pub async fn aborted() {
+    ABORT.wait().await
+}
+
+// Forgets an abort requested while nothing was moving.
+pub fn clear_abort() {
+    ABORT.reset();
+}
+
+// The printer's answer to a cut request bypasses the queue as well, the
+// motion task is waiting for it in the middle of a change.
+static CUT_DONE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub fn cut_done() {
+    CUT_DONE.signal(());
+}
+
+pub async fn cut_confirmed() {
+    CUT_DONE.wait().await
+}
+
+// Forgets a `cut done` that came before the request.
+pub fn clear_cut_done() {
+    CUT_DONE.reset();
+}
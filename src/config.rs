@@ -0,0 +1,647 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use embassy_time::Duration;
+
+use crate::{
+    cutter::CutterKind,
+    error::Error,
+    lane_info::LaneInfo,
+    material::MATERIAL_COUNT,
+    servo::ServoConfig,
+    toolchange::ToolchangeKind,
+    units::{Millimeters, MmPerMin, ServoPulse, StepInterval, Steps, StepsPerMm},
+};
+
+pub const FILAMENT_COUNT: usize = 4;
+
+const SERVO: ServoConfig = ServoConfig {
+    pulse_at_0_deg: ServoPulse(500),
+    pulse_at_180_deg: ServoPulse(2500),
+    min_pulse: ServoPulse(300),
+    max_pulse: ServoPulse(2500),
+    ramp_step: ServoPulse(0),
+    ramp_interval: Duration::from_millis(5),
+    detach: false,
+    detach_delay: Duration::from_millis(500),
+};
+
+const SERVO_RESTING_ANGLE: f32 = 36.0;
+const SERVO_CUTTING_ANGLE: f32 = 99.0;
+
+const PLA_CUT: CutSequence = CutSequence {
+    strokes: &[
+        CutStroke {
+            cutting_angle: SERVO_CUTTING_ANGLE,
+            dwell: Duration::from_millis(750),
+        },
+        CutStroke {
+            cutting_angle: SERVO_CUTTING_ANGLE,
+            dwell: Duration::from_millis(750),
+        },
+        CutStroke {
+            cutting_angle: SERVO_CUTTING_ANGLE,
+            dwell: Duration::from_millis(750),
+        },
+    ],
+    release_dwell: Duration::from_millis(500),
+    wiggle_steps: Steps(0),
+    retries: 0,
+};
+
+// PETG strings, so the blade goes a little deeper and the filament is pulled
+// back and forth between strokes to tear it off. The extra angle, dwells and
+// wiggle are untested starting values, tune them on the real cutter.
+const PETG_CUT: CutSequence = CutSequence {
+    strokes: &[
+        CutStroke {
+            cutting_angle: SERVO_CUTTING_ANGLE,
+            dwell: Duration::from_millis(750),
+        },
+        CutStroke {
+            cutting_angle: SERVO_CUTTING_ANGLE + 4.5,
+            dwell: Duration::from_millis(1_000),
+        },
+        CutStroke {
+            cutting_angle: SERVO_CUTTING_ANGLE + 4.5,
+            dwell: Duration::from_millis(1_000),
+        },
+    ],
+    release_dwell: Duration::from_millis(500),
+    wiggle_steps: Steps(30), // ~0.2mm
+    retries: 0,
+};
+
+// TPU gives way under the blade instead of shearing: slow, deep strokes.
+// Like PETG, these are untested starting values.
+const TPU_CUT: CutSequence = CutSequence {
+    strokes: &[
+        CutStroke {
+            cutting_angle: SERVO_CUTTING_ANGLE + 9.0,
+            dwell: Duration::from_millis(1_000),
+        },
+        CutStroke {
+            cutting_angle: SERVO_CUTTING_ANGLE + 9.0,
+            dwell: Duration::from_millis(1_000),
+        },
+        CutStroke {
+            cutting_angle: SERVO_CUTTING_ANGLE + 9.0,
+            dwell: Duration::from_millis(1_000),
+        },
+        CutStroke {
+            cutting_angle: SERVO_CUTTING_ANGLE + 9.0,
+            dwell: Duration::from_millis(1_000),
+        },
+    ],
+    release_dwell: Duration::from_millis(750),
+    wiggle_steps: Steps(150), // ~1mm
+    retries: 0,
+};
+
+const HOMING_STEPS: Steps = Steps(2624);
+
+// Only used to express selector positions in mm, the lanes stay in steps.
+// Not measured on this selector: move it a known number of steps and set
+// this from the distance travelled before relying on `move <mm>`.
+const SELECTOR_STEPS_PER_MM: StepsPerMm = StepsPerMm(40.0);
+// Homing drives the selector HOMING_STEPS against its end, so that is all
+// the travel there is.
+const SELECTOR_SOFT_LIMITS: (Steps, Steps) = (Steps(0), HOMING_STEPS);
+const SELECTOR_BACKLASH: Backlash = Backlash::None;
+
+// Lanes 0 and 1 sit on one side of the drive gear and lanes 2 and 3 on the
+// other, so they feed with the motor turning opposite ways. Selector
+// positions are steps from home until `calibrate selector` adjusts them, and
+// the selector waits at the neighbour lane so it doesn't pinch the loaded
+// filament.
+const LANE_GEOMETRY: [LaneGeometry; FILAMENT_COUNT] = [
+    LaneGeometry {
+        feed_direction: FeedDirection::DirLow,
+        selector_position: Steps(56),
+        park: SelectorPark::Lane(1),
+        bowden: None,
+    },
+    LaneGeometry {
+        feed_direction: FeedDirection::DirLow,
+        selector_position: Steps(856),
+        park: SelectorPark::Lane(0),
+        bowden: None,
+    },
+    LaneGeometry {
+        feed_direction: FeedDirection::DirHigh,
+        selector_position: Steps(1656),
+        park: SelectorPark::Lane(3),
+        bowden: None,
+    },
+    LaneGeometry {
+        feed_direction: FeedDirection::DirHigh,
+        selector_position: Steps(2456),
+        park: SelectorPark::Lane(2),
+        bowden: None,
+    },
+];
+// Home, only 56 steps before lane 0, so whether the selector clears that lane
+// there depends on the build. No lane parks here by default; check it on the
+// selector before using `SelectorPark::Slot`.
+const SELECTOR_PARK_SLOT: Steps = Steps(0);
+
+const UNLOAD_STEPS: Steps = Steps(14500);
+const FAST_LOAD_STEPS: Steps = Steps(15000); // 98mm
+const SLOW_LOAD_STEPS: Steps = Steps(12500); // 82mm
+
+const EXTRUDER_STEPS_PER_MM: StepsPerMm = StepsPerMm(153.0);
+
+// Bowden calibration feeds the filament at this speed until the sensor
+// triggers, and gives up after CALIBRATION_MAX_MM.
+const CALIBRATION_STEP_SPEED: StepInterval = StepInterval::from_micros(400);
+const CALIBRATION_MAX_MM: Millimeters = Millimeters(400.0);
+// Fed for the extruder calibration, short of the hub.
+const EXTRUDER_CALIBRATION_MM: Millimeters = Millimeters(50.0);
+
+// Each step takes two intervals, high and then low.
+const EXTRUDER_FAST_LOAD_STEP_SPEED: StepInterval = StepInterval::from_micros(133); // 24.57 mm/s (98mm in 4s)
+const EXTRUDER_SLOW_LOAD_STEP_SPEED: StepInterval = StepInterval::from_micros(240); // 13.62 mm/s (82mm in 6s)
+
+const EXTRUDER_STEP_SPEED: StepInterval = StepInterval::from_micros(100); // 32.68 mm/s
+
+// PLA and PETG take the full speed from the first step.
+const PLA: MaterialProfile = MaterialProfile {
+    cut: PLA_CUT,
+    unload_steps: UNLOAD_STEPS,
+    fast_load_steps: FAST_LOAD_STEPS,
+    slow_load_steps: SLOW_LOAD_STEPS,
+    unload_step_speed: EXTRUDER_STEP_SPEED,
+    fast_load_step_speed: EXTRUDER_FAST_LOAD_STEP_SPEED,
+    slow_load_step_speed: EXTRUDER_SLOW_LOAD_STEP_SPEED,
+    ramp: StepRamp::NONE,
+};
+
+const PETG: MaterialProfile = MaterialProfile {
+    cut: PETG_CUT,
+    ..PLA
+};
+
+// TPU buckles when pushed hard, so it moves at about a third of the speed and
+// eases into it. The speeds and ramp are placeholders until tried with real
+// TPU.
+const TPU: MaterialProfile = MaterialProfile {
+    cut: TPU_CUT,
+    unload_steps: UNLOAD_STEPS,
+    fast_load_steps: FAST_LOAD_STEPS,
+    slow_load_steps: SLOW_LOAD_STEPS,
+    unload_step_speed: StepInterval::from_micros(400), // 8.17 mm/s
+    fast_load_step_speed: StepInterval::from_micros(400), // 8.17 mm/s
+    slow_load_step_speed: StepInterval::from_micros(800), // 4.08 mm/s
+    ramp: StepRamp {
+        start: StepInterval::from_micros(1_500),
+        steps: Steps(300), // ~2mm
+    },
+};
+
+// What `extrude` and `retract` accept, covering the tip forming moves.
+const EXTRUDER_FEEDRATE_LIMITS: (MmPerMin, MmPerMin) = (MmPerMin(60.0), MmPerMin(3_600.0));
+const EXTRUDER_MAX_MOVE: Millimeters = Millimeters(100.0);
+
+const SELECTOR_STEP_SPEED: StepInterval = StepInterval::from_micros(500);
+const HOMING_STEP_SPEED: StepInterval = StepInterval::from_micros(1000);
+// A skipped step shifts every lane after it, so the selector can be homed
+// again on its own. Both are off until enabled.
+const REHOME_AFTER_CHANGES: Option<u32> = None;
+const REHOME_WHEN_IDLE: Option<Duration> = None;
+const SELECTOR_DRIFT_TOLERANCE: Steps = Steps(8);
+
+// How long the endswitch has to be held to select each filament, in ms.
+// Anything held for at least HOMING_PRESS_MS triggers homing instead, and two
+// short taps within ABORT_TAP_WINDOW_MS abort the current movement, so a
+// bouncing contact can't.
+const ABORT_PRESS_MS: (u64, u64) = (100, 249);
+const ABORT_TAP_WINDOW_MS: u64 = 1000;
+const SELECT_PRESS_MS: [(u64, u64); FILAMENT_COUNT] =
+    [(250, 750), (751, 1250), (1251, 1750), (1751, 2250)];
+const HOMING_PRESS_MS: u64 = 2750;
+
+// The printer gets a single "stroke" to cut at the toolhead, whatever the
+// material, and the dwell is how long it has to answer `cut done`.
+const PRINTER_CUT: CutSequence = CutSequence {
+    strokes: &[CutStroke {
+        cutting_angle: 0.0,
+        dwell: Duration::from_millis(5_000),
+    }],
+    release_dwell: Duration::from_millis(0),
+    wiggle_steps: Steps(0),
+    retries: 0,
+};
+
+const TIP_FORMING: TipFormingSequence = TipFormingSequence {
+    ramming: &[
+        TipMove {
+            mm: Millimeters(5.0),
+            mm_per_min: MmPerMin(1_200.0),
+        },
+        TipMove {
+            mm: Millimeters(5.0),
+            mm_per_min: MmPerMin(1_800.0),
+        },
+        // up into the cooling zone
+        TipMove {
+            mm: Millimeters(-15.0),
+            mm_per_min: MmPerMin(3_000.0),
+        },
+    ],
+    cooling_moves: 4,
+    cooling_mm: Millimeters(10.0),
+    cooling_mm_per_min: MmPerMin(1_200.0),
+};
+
+#[derive(Clone, Copy, Debug)]
+pub struct TipMove {
+    // Positive towards the nozzle, negative away from it.
+    pub mm: Millimeters,
+    pub mm_per_min: MmPerMin,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TipFormingSequence {
+    // Run in order as soon as the filament is picked up.
+    pub ramming: &'static [TipMove],
+    // Each one down and back up `cooling_mm` in the cooling zone.
+    pub cooling_moves: u32,
+    pub cooling_mm: Millimeters,
+    pub cooling_mm_per_min: MmPerMin,
+}
+
+// How the selector takes up the slack in its drive, which otherwise makes
+// it land short by the backlash whenever it changes direction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backlash {
+    None,
+    // Adds these steps to every move that reverses the last one.
+    Compensate(Steps),
+    // Moves towards home overshoot by these steps and come back, so every
+    // lane is reached moving away from home.
+    FinalApproach(Steps),
+}
+
+// Where the selector waits while a filament is loaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelectorPark {
+    // At the loaded lane, pressing on its filament.
+    Stay,
+    // At the position of another lane.
+    Lane(usize),
+    // At `selector_park_slot`, shared by every lane.
+    Slot,
+    // At a fixed selector position.
+    Position(Steps),
+}
+
+// Measured by the bowden calibration, from the parked filament tip.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BowdenLengths {
+    pub selector_to_hub_mm: Millimeters,
+    pub hub_to_extruder_mm: Millimeters,
+}
+
+// The level of the extruder DIR pin that feeds a lane towards the printer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FeedDirection {
+    DirHigh,
+    DirLow,
+}
+
+// How a lane is built into the MMU.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LaneGeometry {
+    pub feed_direction: FeedDirection,
+    // Selector steps from home.
+    pub selector_position: Steps,
+    // Where the selector waits while the lane is loaded.
+    pub park: SelectorPark,
+    // Calibrated lanes load and unload by their measured lengths, the others
+    // by the step counts of their material.
+    pub bowden: Option<BowdenLengths>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CutStroke {
+    // Degrees.
+    pub cutting_angle: f32,
+    // How long the blade stays down.
+    pub dwell: Duration,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CutSequence {
+    pub strokes: &'static [CutStroke],
+    // How long the blade stays up between strokes.
+    pub release_dwell: Duration,
+    // How far the filament is pulled back and pushed again between strokes,
+    // 0 to keep it still.
+    pub wiggle_steps: Steps,
+    // How often the sequence runs again when the hub still sees the filament
+    // after unloading. The printer waits for every attempt, each of them
+    // unloading again, so none by default.
+    pub retries: u8,
+}
+
+// Extruder moves start at `start` and speed up over `steps` to their own
+// speed, then slow down the same way before they end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StepRamp {
+    pub start: StepInterval,
+    pub steps: Steps,
+}
+
+impl StepRamp {
+    pub const NONE: StepRamp = StepRamp {
+        start: StepInterval::from_micros(0),
+        steps: Steps(0),
+    };
+
+    // The interval of step `step` of a `total` steps move at `speed`.
+    pub fn interval(&self, step: u32, total: Steps, speed: StepInterval) -> StepInterval {
+        let from_end = step.min(total.0.saturating_sub(step + 1));
+        if from_end >= self.steps.0 || self.start <= speed {
+            return speed;
+        }
+        let slower = self.start.0 - speed.0;
+        StepInterval(speed.0 + slower * (self.steps.0 - from_end) / self.steps.0)
+    }
+}
+
+// How filament of one material is moved, picked for each lane by its
+// material.
+#[derive(Clone, Copy, Debug)]
+pub struct MaterialProfile {
+    pub cut: CutSequence,
+    // For lanes without a bowden calibration.
+    pub unload_steps: Steps,
+    pub fast_load_steps: Steps,
+    pub slow_load_steps: Steps,
+    pub unload_step_speed: StepInterval,
+    pub fast_load_step_speed: StepInterval,
+    pub slow_load_step_speed: StepInterval,
+    // Applies to each load and unload move.
+    pub ramp: StepRamp,
+}
+
+/// Everything that shapes how the MMU moves. The firmware and the slicer
+/// profile generator both read from the same `Config`, so the G-code waits
+/// always match what the firmware actually does.
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    pub servo: ServoConfig,
+    pub servo_resting_angle: f32,
+    pub servo_settle_time: Duration,
+    // Whether the loaded filament is cut or gets its tip formed.
+    pub toolchange: ToolchangeKind,
+    pub tip_forming: TipFormingSequence,
+    pub cutter: CutterKind,
+    pub printer_cut: CutSequence,
+    pub material_profiles: [MaterialProfile; MATERIAL_COUNT],
+    // What is in each lane, its material picking the profile.
+    pub lane_info: [LaneInfo; FILAMENT_COUNT],
+
+    pub homing_steps: Steps,
+    pub homing_step_speed: StepInterval,
+    // Skip homing on boot when the journal has a known position.
+    pub resume_after_reset: bool,
+    // Home the selector again, between unloading and selecting, once this
+    // many changes were made since the last homing.
+    pub rehome_after_changes: Option<u32>,
+    // Home again after being idle this long, right away with every lane
+    // parked and otherwise between unloading and selecting at the next
+    // change.
+    pub rehome_when_idle: Option<Duration>,
+    // An endstop at the selector home position, on GPIO26, ends homing
+    // there instead of driving against the end, and measures how far the
+    // selector drifted since it was last homed.
+    pub selector_endstop: bool,
+    pub selector_drift_tolerance: Steps,
+    // A filament sensor at the hub, on GPIO32, confirms the path is clear
+    // before every selector move.
+    pub hub_sensor: bool,
+    // A filament sensor at the printer extruder, on GPIO33, lets the bowden
+    // calibration measure the hub to extruder length too.
+    pub toolhead_sensor: bool,
+
+    pub lane_geometry: [LaneGeometry; FILAMENT_COUNT],
+    pub selector_park_slot: Steps,
+    // Waits at the lane most likely to be loaded next instead, once there
+    // were changes to guess it from.
+    pub selector_park_near_next: bool,
+    pub selector_step_speed: StepInterval,
+    pub selector_steps_per_mm: StepsPerMm,
+    // Selector moves outside these positions are refused.
+    pub selector_soft_limits: (Steps, Steps),
+    pub selector_backlash: Backlash,
+
+    pub extruder_steps_per_mm: StepsPerMm,
+    pub extruder_step_speed: StepInterval,
+    pub extruder_feedrate_limits: (MmPerMin, MmPerMin),
+    pub extruder_max_move: Millimeters,
+    pub calibration_step_speed: StepInterval,
+    pub calibration_max_mm: Millimeters,
+    pub extruder_calibration_mm: Millimeters,
+
+    pub abort_press_ms: (u64, u64),
+    pub abort_tap_window_ms: u64,
+    pub select_press_ms: [(u64, u64); FILAMENT_COUNT],
+    pub homing_press_ms: u64,
+}
+
+impl Config {
+    pub const DEFAULT: Config = Config {
+        servo: SERVO,
+        servo_resting_angle: SERVO_RESTING_ANGLE,
+        servo_settle_time: Duration::from_millis(2_000),
+        toolchange: ToolchangeKind::Cut,
+        tip_forming: TIP_FORMING,
+        cutter: CutterKind::Servo,
+        printer_cut: PRINTER_CUT,
+        material_profiles: [PLA, PETG, TPU],
+        lane_info: [LaneInfo::DEFAULT; FILAMENT_COUNT],
+
+        homing_steps: HOMING_STEPS,
+        homing_step_speed: HOMING_STEP_SPEED,
+        resume_after_reset: false,
+        rehome_after_changes: REHOME_AFTER_CHANGES,
+        rehome_when_idle: REHOME_WHEN_IDLE,
+        selector_endstop: false,
+        selector_drift_tolerance: SELECTOR_DRIFT_TOLERANCE,
+        hub_sensor: false,
+        toolhead_sensor: false,
+
+        lane_geometry: LANE_GEOMETRY,
+        selector_park_slot: SELECTOR_PARK_SLOT,
+        selector_park_near_next: false,
+        selector_step_speed: SELECTOR_STEP_SPEED,
+        selector_steps_per_mm: SELECTOR_STEPS_PER_MM,
+        selector_soft_limits: SELECTOR_SOFT_LIMITS,
+        selector_backlash: SELECTOR_BACKLASH,
+
+        extruder_steps_per_mm: EXTRUDER_STEPS_PER_MM,
+        extruder_step_speed: EXTRUDER_STEP_SPEED,
+        extruder_feedrate_limits: EXTRUDER_FEEDRATE_LIMITS,
+        extruder_max_move: EXTRUDER_MAX_MOVE,
+        calibration_step_speed: CALIBRATION_STEP_SPEED,
+        calibration_max_mm: CALIBRATION_MAX_MM,
+        extruder_calibration_mm: EXTRUDER_CALIBRATION_MM,
+
+        abort_press_ms: ABORT_PRESS_MS,
+        abort_tap_window_ms: ABORT_TAP_WINDOW_MS,
+        select_press_ms: SELECT_PRESS_MS,
+        homing_press_ms: HOMING_PRESS_MS,
+    };
+
+    pub fn profile(&self, filament: usize) -> &MaterialProfile {
+        &self.material_profiles[self.lane_info[filament].material.index()]
+    }
+
+    pub fn cut_sequence(&self, filament: usize) -> &CutSequence {
+        if self.cutter == CutterKind::Printer {
+            return &self.printer_cut;
+        }
+        &self.profile(filament).cut
+    }
+
+    pub fn lane_position(&self, filament: usize) -> Steps {
+        self.lane_geometry[filament].selector_position
+    }
+
+    // The extruder DIR pin level that feeds `filament` towards the printer.
+    pub fn load_direction(&self, filament: usize) -> bool {
+        self.lane_geometry[filament].feed_direction == FeedDirection::DirHigh
+    }
+
+    pub fn unload_direction(&self, filament: usize) -> bool {
+        !self.load_direction(filament)
+    }
+
+    // Where the selector waits while `filament` is loaded, given the lane
+    // expected to be loaded after it.
+    pub fn park_position(&self, filament: usize, next: Option<usize>) -> Steps {
+        match next {
+            Some(next) if self.selector_park_near_next && next != filament => {
+                self.lane_position(next)
+            }
+            _ => match self.lane_geometry[filament].park {
+                SelectorPark::Stay => self.lane_position(filament),
+                SelectorPark::Lane(lane) => self.lane_position(lane),
+                SelectorPark::Slot => self.selector_park_slot,
+                SelectorPark::Position(position) => position,
+            },
+        }
+    }
+
+    // Whether the selector can wait at `position` while `filament` is loaded.
+    pub fn may_park_at(&self, filament: usize, position: Steps) -> bool {
+        position == self.park_position(filament, None)
+            || (self.selector_park_near_next
+                && self
+                    .lane_geometry
+                    .iter()
+                    .any(|lane| lane.selector_position == position))
+    }
+
+    pub fn is_within_travel(&self, position: Steps) -> bool {
+        let (min, max) = self.selector_soft_limits;
+        (min..=max).contains(&position)
+    }
+
+    // The selector position `mm` from home, if the selector can go there.
+    pub fn selector_position(&self, mm: Millimeters) -> Result<Steps, Error> {
+        self.selector_steps_per_mm
+            .steps(mm)
+            .filter(|&position| self.is_within_travel(position))
+            .ok_or(Error::OutsideTravel)
+    }
+
+    // The overshoot of a final approach to `to`, short enough to stay within
+    // the soft limits.
+    pub fn final_approach_overshoot(&self, to: Steps, overshoot: Steps) -> Steps {
+        let (min, _) = self.selector_soft_limits;
+        overshoot.min(to.saturating_sub(min))
+    }
+
+    pub fn selector_position_mm(&self, position: Steps) -> Millimeters {
+        self.selector_steps_per_mm.mm(position)
+    }
+
+    // Checks an extrude or retract of `mm` at `speed` against the limits,
+    // and converts it to steps.
+    pub fn extruder_move(
+        &self,
+        mm: Millimeters,
+        speed: MmPerMin,
+    ) -> Result<(Steps, StepInterval), Error> {
+        let (min_speed, max_speed) = self.extruder_feedrate_limits;
+        if !(min_speed..=max_speed).contains(&speed) {
+            return Err(Error::InvalidFeedrate);
+        }
+        if !(Millimeters(0.0)..=self.extruder_max_move).contains(&mm) {
+            return Err(Error::InvalidLength);
+        }
+        let steps_per_mm = self.extruder_steps_per_mm;
+        let steps = steps_per_mm.steps(mm).ok_or(Error::InvalidLength)?;
+        let interval = steps_per_mm.interval(speed).ok_or(Error::InvalidFeedrate)?;
+        Ok((steps, interval))
+    }
+
+    // The fast phase of the load brings the filament to the hub, the slow
+    // one from there into the printer extruder. Lanes without a usable
+    // calibration use the default step counts.
+    pub fn fast_load_steps_for(&self, filament: usize) -> Steps {
+        self.lane_geometry[filament]
+            .bowden
+            .and_then(|lengths| self.extruder_steps_per_mm.steps(lengths.selector_to_hub_mm))
+            .unwrap_or(self.profile(filament).fast_load_steps)
+    }
+
+    pub fn slow_load_steps_for(&self, filament: usize) -> Steps {
+        self.lane_geometry[filament]
+            .bowden
+            .and_then(|lengths| self.extruder_steps_per_mm.steps(lengths.hub_to_extruder_mm))
+            .unwrap_or(self.profile(filament).slow_load_steps)
+    }
+
+    // With a calibrated bowden, a cut leaves the filament end at the hub and
+    // a formed tip comes all the way back from the printer extruder. Other
+    // lanes unload by the default step count either way.
+    pub fn unload_steps_for(&self, filament: usize) -> Steps {
+        match (self.toolchange, self.lane_geometry[filament].bowden) {
+            (ToolchangeKind::Cut, Some(_)) => self.fast_load_steps_for(filament),
+            (ToolchangeKind::TipForming, Some(_)) => {
+                self.fast_load_steps_for(filament) + self.slow_load_steps_for(filament)
+            }
+            (_, None) => self.profile(filament).unload_steps,
+        }
+    }
+
+    // Which filament a press of `press_ms` on the endswitch selects.
+    pub fn filament_for_press(&self, press_ms: u64) -> Option<usize> {
+        self.select_press_ms
+            .iter()
+            .position(|&(min, max)| (min..=max).contains(&press_ms))
+    }
+
+    pub fn is_abort_press(&self, press_ms: u64) -> bool {
+        let (min, max) = self.abort_press_ms;
+        (min..=max).contains(&press_ms)
+    }
+}
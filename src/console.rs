@@ -0,0 +1,244 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Line based commands over the USB serial port, next to the log output:
+//
+//     home            home the selector
+//     select <lane>   change to a lane
+//     move <mm>       move the selector to a position from home
+//     abort           stop the current movement
+//     cut done        the printer finished a requested cut
+//     status          print the selected lane, position and lane states
+//     park <lane>     declare a lane parked after clearing it by hand
+//     material <lane> <pla|petg|tpu>
+//                     set the material profile a lane is cut and moved with
+//     colour <lane> <rrggbb|none>
+//     temp <lane> <min> <max>|none
+//     spool <lane> <id|none>
+//     remaining <lane> <mm|none>
+//                     record what is loaded in a lane, shown by status
+//     toolchange <cut|tip>
+//                     cut the filament or form its tip before unloading
+//     backlash <none|compensate|approach> [steps]
+//                     how the selector takes up backlash
+//     parkat <lane> <stay|slot|lane <lane>|steps <position>>
+//                     where the selector waits while a lane is loaded
+//     calibrate bowden <lane>
+//                     measure a lane's bowden lengths with the sensors
+//     calibrate selector <lane>
+//                     move to a lane to adjust its selector position
+//     jog <steps>     move the selector by signed steps while adjusting
+//     calibrate done  store the adjusted selector position
+//     calibrate extruder <lane>
+//                     feed a lane a known length to calibrate steps/mm
+//     calibrate measured <mm>
+//                     enter the length that was actually fed
+
+use esp_hal::{uart::UartRx, Async};
+use esp_println::println;
+
+use crate::{
+    command::{self, Command},
+    config::{Backlash, SelectorPark, FILAMENT_COUNT},
+    lane_info::{LaneInfoUpdate, Rgb},
+    material::Material,
+    status,
+    toolchange::ToolchangeKind,
+    units::{Millimeters, Steps},
+};
+
+const LINE_LENGTH: usize = 32;
+
+pub async fn run_console(mut rx: UartRx<'_, Async>) {
+    let mut line = [0u8; LINE_LENGTH];
+    let mut length = 0;
+    let mut overflow = false;
+    let mut buffer = [0u8; 16];
+    loop {
+        let count = match rx.read_async(&mut buffer).await {
+            Ok(count) => count,
+            Err(error) => {
+                log::warn!("Console read failed: {:?}", error);
+                continue;
+            }
+        };
+
+        for &byte in &buffer[..count] {
+            match byte {
+                b'\r' | b'\n' => {
+                    if overflow {
+                        println!("error: line too long");
+                    } else if let Ok(text) = core::str::from_utf8(&line[..length]) {
+                        execute(text.trim()).await;
+                    }
+                    length = 0;
+                    overflow = false;
+                }
+                _ if length < LINE_LENGTH => {
+                    line[length] = byte;
+                    length += 1;
+                }
+                _ => overflow = true,
+            }
+        }
+    }
+}
+
+async fn execute(line: &str) {
+    let mut words = line.split_whitespace();
+    let Some(name) = words.next() else {
+        return;
+    };
+    let argument = words.next();
+    let value = words.next();
+    let lane = argument.and_then(parse_lane);
+
+    let command = match (name, lane) {
+        ("abort", _) => {
+            command::abort();
+            println!("ok");
+            return;
+        }
+        ("status", _) => {
+            print_status();
+            println!("ok");
+            return;
+        }
+        ("cut", _) if argument == Some("done") => {
+            command::cut_done();
+            println!("ok");
+            return;
+        }
+        ("home", _) => Some(Command::Home),
+        ("select", Some(lane)) => Some(Command::Select(lane)),
+        ("move", _) => argument
+            .and_then(|word| word.parse::<f32>().ok())
+            .map(|mm| Command::MoveSelector(Millimeters(mm))),
+        ("park", Some(lane)) => Some(Command::MarkParked(lane)),
+        ("material", Some(lane)) => value
+            .and_then(Material::from_name)
+            .map(|material| Command::SetMaterial(lane, material)),
+        ("jog", _) => argument
+            .and_then(|word| word.parse::<i32>().ok())
+            .map(Command::JogSelector),
+        ("backlash", _) => {
+            let steps = value.and_then(|word| word.parse::<u32>().ok()).map(Steps);
+            match (argument, steps) {
+                (Some("none"), _) => Some(Command::SetBacklash(Backlash::None)),
+                (Some("compensate"), Some(steps)) => {
+                    Some(Command::SetBacklash(Backlash::Compensate(steps)))
+                }
+                (Some("approach"), Some(steps)) => {
+                    Some(Command::SetBacklash(Backlash::FinalApproach(steps)))
+                }
+                _ => None,
+            }
+        }
+        ("parkat", Some(lane)) => {
+            let position = words.next();
+            let park = match value {
+                Some("stay") => Some(SelectorPark::Stay),
+                Some("slot") => Some(SelectorPark::Slot),
+                Some("lane") => position.and_then(parse_lane).map(SelectorPark::Lane),
+                Some("steps") => position
+                    .and_then(|word| word.parse::<u32>().ok())
+                    .map(|steps| SelectorPark::Position(Steps(steps))),
+                _ => None,
+            };
+            park.map(|park| Command::SetSelectorPark(lane, park))
+        }
+        ("colour", Some(lane)) => match value {
+            Some("none") => Some(LaneInfoUpdate::Colour(None)),
+            _ => value
+                .and_then(Rgb::from_hex)
+                .map(|colour| LaneInfoUpdate::Colour(Some(colour))),
+        }
+        .map(|update| Command::UpdateLaneInfo(lane, update)),
+        ("temp", Some(lane)) => match (value, words.next()) {
+            (Some("none"), _) => Some(LaneInfoUpdate::NozzleTemp(None)),
+            (Some(min), Some(max)) => min
+                .parse::<u16>()
+                .ok()
+                .zip(max.parse::<u16>().ok())
+                .map(|temps| LaneInfoUpdate::NozzleTemp(Some(temps))),
+            _ => None,
+        }
+        .map(|update| Command::UpdateLaneInfo(lane, update)),
+        ("spool", Some(lane)) => match value {
+            Some("none") => Some(LaneInfoUpdate::SpoolId(None)),
+            _ => value
+                .and_then(|word| word.parse::<u32>().ok())
+                .map(|id| LaneInfoUpdate::SpoolId(Some(id))),
+        }
+        .map(|update| Command::UpdateLaneInfo(lane, update)),
+        ("remaining", Some(lane)) => match value {
+            Some("none") => Some(LaneInfoUpdate::Remaining(None)),
+            _ => value
+                .and_then(|word| word.parse::<f32>().ok())
+                .map(|mm| LaneInfoUpdate::Remaining(Some(Millimeters(mm)))),
+        }
+        .map(|update| Command::UpdateLaneInfo(lane, update)),
+        ("toolchange", _) => argument
+            .and_then(ToolchangeKind::from_name)
+            .map(Command::SetToolchange),
+        ("calibrate", _) => match (argument, value.and_then(parse_lane)) {
+            (Some("bowden"), Some(lane)) => Some(Command::CalibrateBowden(lane)),
+            (Some("selector"), Some(lane)) => Some(Command::CalibrateSelector(lane)),
+            (Some("done"), _) => Some(Command::FinishSelectorCalibration),
+            (Some("extruder"), Some(lane)) => Some(Command::CalibrateExtruder(lane)),
+            (Some("measured"), _) => value
+                .and_then(|word| word.parse::<f32>().ok())
+                .map(|mm| Command::FinishExtruderCalibration(Millimeters(mm))),
+            _ => None,
+        },
+        _ => None,
+    };
+    let Some(command) = command else {
+        println!("error: unknown command '{}'", line);
+        return;
+    };
+    command::send(command).await;
+    println!("ok");
+}
+
+fn parse_lane(word: &str) -> Option<usize> {
+    word.parse::<usize>()
+        .ok()
+        .filter(|&lane| lane < FILAMENT_COUNT)
+}
+
+fn print_status() {
+    let status = status::get();
+    match status.current_filament {
+        Some(filament) => println!("filament: {}", filament),
+        None => println!("filament: none"),
+    }
+    match status.current_position {
+        Some(position) => println!("position: {}", position),
+        None => println!("position: unknown"),
+    }
+    let lane_info = status::lane_info();
+    for (filament, state) in status.lanes.iter().enumerate() {
+        println!(
+            "lane {}: {}, {}",
+            filament,
+            state.name(),
+            lane_info[filament]
+        );
+    }
+}
@@ -0,0 +1,211 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// The hardware that cuts the filament before an unload. The filament
+// changer runs the cut sequence and the cutter only moves its blade, so
+// every build shares the same change flow.
+
+use esp_hal::gpio::Output;
+use esp_println::println;
+
+use crate::{
+    command,
+    config::{Config, CutStroke},
+    error::Error,
+    events::{self, Event},
+    motion::{self, Watchdog},
+    servo::{Servo, ServoConfig},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CutterKind {
+    // Blade on the MCPWM servo.
+    Servo,
+    // Blade pushed by a solenoid on GPIO25 while it is high.
+    Solenoid,
+    // No cutter on the MMU: the printer is asked to cut at the toolhead.
+    Printer,
+}
+
+pub trait Cutter {
+    // One stroke of the cut sequence: through the filament and back out.
+    async fn stroke(
+        &mut self,
+        watchdog: &mut Watchdog,
+        filament: usize,
+        stroke: &CutStroke,
+    ) -> Result<(), Error>;
+
+    // Leaves the blade out of the filament path once the sequence is done.
+    async fn open(&mut self, watchdog: &mut Watchdog) -> Result<(), Error>;
+
+    // Takes the blade out of the filament path right away, after a failure.
+    fn release(&mut self);
+}
+
+pub struct ServoCutter<'a> {
+    servo: Servo<'a>,
+    config: ServoConfig,
+    resting_angle: f32,
+}
+
+impl<'a> ServoCutter<'a> {
+    pub fn new(servo: Servo<'a>, config: &Config) -> Self {
+        Self {
+            servo,
+            config: config.servo,
+            resting_angle: config.servo_resting_angle,
+        }
+    }
+
+    // Ramps the servo to `angle`, if ramping is configured.
+    async fn move_to(&mut self, watchdog: &mut Watchdog, angle: f32) -> Result<(), Error> {
+        while self.servo.step_towards(angle) {
+            motion::wait(watchdog, self.config.ramp_interval).await?;
+        }
+        Ok(())
+    }
+}
+
+impl Cutter for ServoCutter<'_> {
+    async fn stroke(
+        &mut self,
+        watchdog: &mut Watchdog,
+        _filament: usize,
+        stroke: &CutStroke,
+    ) -> Result<(), Error> {
+        self.move_to(watchdog, stroke.cutting_angle).await?;
+        motion::wait(watchdog, stroke.dwell).await?;
+        // move servo back to resting position
+        self.move_to(watchdog, self.resting_angle).await
+    }
+
+    async fn open(&mut self, watchdog: &mut Watchdog) -> Result<(), Error> {
+        self.move_to(watchdog, self.resting_angle).await?;
+        if self.config.detach {
+            motion::wait(watchdog, self.config.detach_delay).await?;
+            self.servo.detach();
+        }
+        Ok(())
+    }
+
+    fn release(&mut self) {
+        self.servo.set_angle(self.resting_angle);
+    }
+}
+
+pub struct SolenoidCutter<'a> {
+    solenoid: Output<'a>,
+}
+
+impl<'a> SolenoidCutter<'a> {
+    pub fn new(solenoid: Output<'a>) -> Self {
+        Self { solenoid }
+    }
+}
+
+impl Cutter for SolenoidCutter<'_> {
+    async fn stroke(
+        &mut self,
+        watchdog: &mut Watchdog,
+        _filament: usize,
+        stroke: &CutStroke,
+    ) -> Result<(), Error> {
+        self.solenoid.set_high();
+        let result = motion::wait(watchdog, stroke.dwell).await;
+        self.solenoid.set_low();
+        result
+    }
+
+    async fn open(&mut self, _watchdog: &mut Watchdog) -> Result<(), Error> {
+        self.solenoid.set_low();
+        Ok(())
+    }
+
+    fn release(&mut self) {
+        self.solenoid.set_low();
+    }
+}
+
+// Prints a cut request for the printer host on the console and waits for it
+// to answer `cut done`, for at most the stroke's dwell.
+pub struct PrinterCutter;
+
+impl Cutter for PrinterCutter {
+    async fn stroke(
+        &mut self,
+        watchdog: &mut Watchdog,
+        filament: usize,
+        stroke: &CutStroke,
+    ) -> Result<(), Error> {
+        command::clear_cut_done();
+        println!("request: cut {}", filament);
+        events::publish(Event::CutRequested { filament });
+        if motion::wait_for(watchdog, command::cut_confirmed(), stroke.dwell).await? {
+            Ok(())
+        } else {
+            log::warn!("No reply to the cut request for filament {}", filament);
+            Err(Error::CutNotConfirmed { filament })
+        }
+    }
+
+    async fn open(&mut self, _watchdog: &mut Watchdog) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn release(&mut self) {}
+}
+
+// Embassy tasks can't be generic, so the configured cutter is picked at
+// runtime through this enum.
+pub enum AnyCutter<'a> {
+    Servo(ServoCutter<'a>),
+    Solenoid(SolenoidCutter<'a>),
+    Printer(PrinterCutter),
+}
+
+impl Cutter for AnyCutter<'_> {
+    async fn stroke(
+        &mut self,
+        watchdog: &mut Watchdog,
+        filament: usize,
+        stroke: &CutStroke,
+    ) -> Result<(), Error> {
+        match self {
+            AnyCutter::Servo(cutter) => cutter.stroke(watchdog, filament, stroke).await,
+            AnyCutter::Solenoid(cutter) => cutter.stroke(watchdog, filament, stroke).await,
+            AnyCutter::Printer(cutter) => cutter.stroke(watchdog, filament, stroke).await,
+        }
+    }
+
+    async fn open(&mut self, watchdog: &mut Watchdog) -> Result<(), Error> {
+        match self {
+            AnyCutter::Servo(cutter) => cutter.open(watchdog).await,
+            AnyCutter::Solenoid(cutter) => cutter.open(watchdog).await,
+            AnyCutter::Printer(cutter) => cutter.open(watchdog).await,
+        }
+    }
+
+    fn release(&mut self) {
+        match self {
+            AnyCutter::Servo(cutter) => cutter.release(),
+            AnyCutter::Solenoid(cutter) => cutter.release(),
+            AnyCutter::Printer(cutter) => cutter.release(),
+        }
+    }
+}
@@ -0,0 +1,78 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    // The endswitch was held for a time that doesn't map to any command.
+    UnexpectedPress { duration_ms: u64 },
+    // The command was interrupted by an abort.
+    Aborted,
+    // The selector position is unknown, it has to be homed first.
+    NotHomed,
+    // The filament of this lane is still in the path of the selector.
+    LaneEngaged { filament: usize },
+    // The hub sensor reports filament although every lane is parked, or still
+    // after a lane was unloaded.
+    HubOccupied,
+    // The calibration needs a sensor that isn't configured.
+    NoSensor,
+    // The filament was fed the calibration's maximum length without reaching
+    // the sensor.
+    SensorNotReached { filament: usize },
+    // The command only makes sense during a calibration that isn't running.
+    NotCalibrating,
+    // A measured length entered for a calibration is not plausible.
+    InvalidMeasurement,
+    // Lane information that can't be stored, e.g. a reversed temperature
+    // range.
+    InvalidLaneInfo,
+    // The target is outside the selector soft limits.
+    OutsideTravel,
+    // Homing covered the whole travel without reaching the selector endstop.
+    EndstopNotReached,
+    // An extruder move longer than `extruder_max_move`, or negative.
+    InvalidLength,
+    // An extruder move outside `extruder_feedrate_limits`.
+    InvalidFeedrate,
+    // The printer didn't answer a cut request with `cut done` in time.
+    CutNotConfirmed { filament: usize },
+}
+
+impl Error {
+    // Whether the MMU is left in an unsafe or unknown state, rather than
+    // just refusing a command it was given.
+    pub fn is_fault(self) -> bool {
+        match self {
+            Error::Aborted
+            | Error::NotHomed
+            | Error::LaneEngaged { .. }
+            | Error::HubOccupied
+            | Error::SensorNotReached { .. }
+            | Error::EndstopNotReached
+            | Error::CutNotConfirmed { .. } => true,
+            Error::UnexpectedPress { .. }
+            | Error::NoSensor
+            | Error::NotCalibrating
+            | Error::InvalidMeasurement
+            | Error::InvalidLaneInfo
+            | Error::OutsideTravel
+            | Error::InvalidLength
+            | Error::InvalidFeedrate => false,
+        }
+    }
+}
@@ -0,0 +1,85 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// MMU lifecycle events. The filament changer publishes what it is doing here
+// and anything interested (LED, comms, statistics, printer signals) subscribes
+// on its own, without the changer knowing about it.
+
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    pubsub::{PubSubChannel, Subscriber},
+};
+
+use crate::{error::Error, lanes::LaneState};
+
+const EVENT_CAPACITY: usize = 8;
+const MAX_SUBSCRIBERS: usize = 4;
+const MAX_PUBLISHERS: usize = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sensor {
+    Endswitch,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    HomingStarted,
+    HomingCompleted,
+    CutRequested { filament: usize },
+    CutDone { filament: usize },
+    TipFormed { filament: usize },
+    Unloaded { filament: usize },
+    LaneSelected { filament: usize },
+    Loaded { filament: usize },
+    LaneChanged { filament: usize, state: LaneState },
+    Error(Error),
+    SensorChanged { sensor: Sensor, triggered: bool },
+    // Homing found the selector this many steps further from home than it
+    // should have been, negative for closer.
+    SelectorDrift { steps: i32 },
+}
+
+pub type EventSubscriber = Subscriber<
+    'static,
+    CriticalSectionRawMutex,
+    Event,
+    EVENT_CAPACITY,
+    MAX_SUBSCRIBERS,
+    MAX_PUBLISHERS,
+>;
+
+static EVENTS: PubSubChannel<
+    CriticalSectionRawMutex,
+    Event,
+    EVENT_CAPACITY,
+    MAX_SUBSCRIBERS,
+    MAX_PUBLISHERS,
+> = PubSubChannel::new();
+
+// Never waits: if a subscriber falls behind it misses the oldest events
+// instead of stalling the motion that published them.
+pub fn publish(event: Event) {
+    log::debug!("Event: {:?}", event);
+    EVENTS.immediate_publisher().publish_immediate(event);
+}
+
+pub fn subscribe() -> EventSubscriber {
+    EVENTS
+        .subscriber()
+        .expect("more event subscribers than MAX_SUBSCRIBERS")
+}
@@ -16,57 +16,48 @@ You should have received a copy of the GNU General Public License
 along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
+use embassy_futures::select::{select, Either};
 use embassy_time::{Duration, Instant, Timer};
-use esp_hal::{
-    gpio::{Input, Output},
-    mcpwm::operator::PwmPin,
-    peripherals::MCPWM0,
-};
+use esp_hal::gpio::{Input, Output};
 
-// Servo Motor Limits:
-//     300 is min
-//     2500 is max
-//     0deg is 500, 90deg is 1500, 180deg is 2500
-//     90deg is 1500
-//     180deg is 2500
-const SERVO_RESTING_POSITION: u16 = 900;
-const SERVO_CUTTING_POSITION: u16 = 1600;
-
-const HOMING_STEPS: u32 = 2624;
-
-const FILAMENT_START_OFFSET: u32 = 56;
-const FILAMENT_DISTANCE: u32 = 800;
-const FILAMENT_POSITIONS: [u32; 4] = [
-    0 * FILAMENT_DISTANCE + FILAMENT_START_OFFSET,
-    1 * FILAMENT_DISTANCE + FILAMENT_START_OFFSET,
-    2 * FILAMENT_DISTANCE + FILAMENT_START_OFFSET,
-    3 * FILAMENT_DISTANCE + FILAMENT_START_OFFSET,
-];
-
-const FILAMENT_RESTING_POSITIONS: [u32; 4] = [
-    FILAMENT_POSITIONS[1],
-    FILAMENT_POSITIONS[0],
-    FILAMENT_POSITIONS[3],
-    FILAMENT_POSITIONS[2],
-];
-
-const UNLOAD_STEPS: u32 = 14500;
-const FAST_LOAD_STEPS: u32 = 15000; // 98mm
-
-const EXTRUDER_STEPS_PER_MM: u32 = 153;
-
-fn mm_to_steps(mm: f32) -> u32 {
-    (mm * EXTRUDER_STEPS_PER_MM as f32) as u32
-}
+use crate::{
+    command::{self, Command},
+    config::{Backlash, BowdenLengths, Config, SelectorPark, StepRamp, FILAMENT_COUNT},
+    cutter::{AnyCutter, Cutter},
+    error::Error,
+    events::{self, Event},
+    lane_info::LaneInfo,
+    lanes::{self, LaneState, Lanes, UNKNOWN_LANES},
+    motion::{self, Watchdog},
+    settings::SettingsStore,
+    status,
+    storage::{self, Snapshot},
+    timing,
+    toolchange::{ToolchangeContext, ToolchangeStrategy},
+    units::{Millimeters, MmPerMin, StepInterval, Steps, StepsPerMm},
+};
 
-const SLOW_LOAD_STEPS: u32 = 12500; // 82mm
-const EXTRUDER_FAST_LOAD_STEP_SPEED: Duration = Duration::from_micros(133); // 49.12 mm/s (98mm in 2s)
-const EXTRUDER_SLOW_LOAD_STEP_SPEED: Duration = Duration::from_micros(240); // 27.33 mm/s (82mm in 3s)
+// How often the watchdog is fed while idle, well within its timeout.
+const WATCHDOG_FEED_INTERVAL: Duration = Duration::from_millis(1_000);
 
-const EXTRUDER_STEP_SPEED: Duration = Duration::from_micros(100);
+// Selector use since the last homing, which decides when to home again.
+#[derive(Clone, Copy, Debug, Default)]
+struct Odometer {
+    changes: u32,
+    moves: u32,
+    travel: Steps,
+    // Idle long enough while loaded, so the next change homes again.
+    idle: bool,
+}
 
-const SELECTOR_STEP_SPEED: Duration = Duration::from_micros(500);
-const HOMING_STEP_SPEED: Duration = Duration::from_micros(1000);
+// A calibration waiting for input from the user.
+#[derive(Clone, Copy, Debug)]
+enum Calibration {
+    // The selector position of this lane is being adjusted with `jog`.
+    Selector(usize),
+    // `steps` of this lane were fed and are being measured.
+    Extruder { filament: usize, steps: Steps },
+}
 
 pub struct FilamentChanger<'a> {
     stepper_a_selector_dir: Output<'a>,
@@ -75,11 +66,25 @@ pub struct FilamentChanger<'a> {
     stepper_b_extruder_dir: Output<'a>,
     stepper_b_extruder_step: Output<'a>,
     stepper_b_extruder_en: Output<'a>,
-    endswitch: Input<'a>,
-    led: Output<'a>,
-    pwm_pin: PwmPin<'a, MCPWM0, 0, true>,
+    cutter: AnyCutter<'a>,
+    hub_sensor: Option<Input<'a>>,
+    toolhead_sensor: Option<Input<'a>>,
+    selector_endstop: Option<Input<'a>>,
+    config: Config,
+    // The state journaled before the last reset, until it is restored.
+    journaled: Option<Snapshot>,
+    settings: SettingsStore,
+    watchdog: Watchdog,
     current_filament: Option<usize>,
-    current_position: u32,
+    // None until homed, and again after any interrupted move.
+    current_position: Option<Steps>,
+    lanes: Lanes,
+    calibration: Option<Calibration>,
+    // Which way the selector last moved, forward being away from home.
+    selector_direction: Option<bool>,
+    odometer: Odometer,
+    // How often each lane followed each other lane, to guess the next one.
+    transitions: [[u32; FILAMENT_COUNT]; FILAMENT_COUNT],
 }
 
 impl<'a> FilamentChanger<'a> {
@@ -90,9 +95,14 @@ impl<'a> FilamentChanger<'a> {
         stepper_b_dir: Output<'a>,
         stepper_b_step: Output<'a>,
         stepper_b_en: Output<'a>,
-        endswitch: Input<'a>,
-        led: Output<'a>,
-        pwm_pin: PwmPin<'a, MCPWM0, 0, true>,
+        cutter: AnyCutter<'a>,
+        hub_sensor: Option<Input<'a>>,
+        toolhead_sensor: Option<Input<'a>>,
+        selector_endstop: Option<Input<'a>>,
+        config: Config,
+        journaled: Option<Snapshot>,
+        settings: SettingsStore,
+        watchdog: Watchdog,
     ) -> Self {
         Self {
             stepper_a_selector_dir: stepper_a_dir,
@@ -101,94 +111,417 @@ impl<'a> FilamentChanger<'a> {
             stepper_b_extruder_dir: stepper_b_dir,
             stepper_b_extruder_step: stepper_b_step,
             stepper_b_extruder_en: stepper_b_en,
-            led,
-            endswitch,
-            pwm_pin,
+            cutter,
+            hub_sensor,
+            toolhead_sensor,
+            selector_endstop,
+            config,
+            journaled,
+            settings,
+            watchdog,
             current_filament: None,
-            current_position: 0,
+            current_position: None,
+            lanes: UNKNOWN_LANES,
+            calibration: None,
+            selector_direction: None,
+            odometer: Odometer::default(),
+            transitions: [[0; FILAMENT_COUNT]; FILAMENT_COUNT],
+        }
+    }
+
+    // Feeds the selected filament towards the printer, in the feed direction
+    // of its lane. With nothing selected DIR is high.
+    pub async fn extrude(&mut self, mm: Millimeters, speed: MmPerMin) -> Result<(), Error> {
+        let (steps, step_duration) = self.config.extruder_move(mm, speed)?;
+
+        let direction = self
+            .current_filament
+            .is_none_or(|filament| self.config.load_direction(filament));
+        self.move_stepper_extruder(steps, direction, step_duration)
+            .await
+    }
+
+    // The opposite of `extrude`. With nothing selected DIR is low.
+    pub async fn retract(&mut self, mm: Millimeters, speed: MmPerMin) -> Result<(), Error> {
+        let (steps, step_duration) = self.config.extruder_move(mm, speed)?;
+
+        let direction = self
+            .current_filament
+            .is_some_and(|filament| self.config.unload_direction(filament));
+        self.move_stepper_extruder(steps, direction, step_duration)
+            .await
+    }
+
+    fn config(&self) -> &Config {
+        &self.config
+    }
+
+    fn position(&self) -> Result<Steps, Error> {
+        self.current_position.ok_or(Error::NotHomed)
+    }
+
+    fn persist(&mut self) {
+        let snapshot = Snapshot {
+            current_filament: self.current_filament,
+            current_position: self.current_position,
+            lanes: self.lanes,
+        };
+        storage::record(snapshot);
+        status::set(snapshot);
+    }
+
+    fn set_lane(&mut self, filament: usize, state: LaneState) {
+        if self.lanes[filament] == state {
+            return;
+        }
+        log::info!("Lane {} is {}", filament, state.name());
+        self.lanes[filament] = state;
+        self.persist();
+        events::publish(Event::LaneChanged { filament, state });
+    }
+
+    fn hub_occupied(&self) -> bool {
+        self.hub_sensor
+            .as_ref()
+            .is_some_and(|hub_sensor| hub_sensor.is_high())
+    }
+
+    fn toolhead_triggered(&self) -> bool {
+        self.toolhead_sensor
+            .as_ref()
+            .is_some_and(|toolhead_sensor| toolhead_sensor.is_high())
+    }
+
+    // Every selector move starts here, and is refused unless the target is
+    // within the soft limits and the lane states and the hub sensor agree
+    // that the path is clear. The position is journaled as unknown for the
+    // duration of the move, so a reset mid-move is never mistaken for a known
+    // state.
+    fn begin_selector_move(&mut self, to: Steps) -> Result<(), Error> {
+        if !self.config.is_within_travel(to) {
+            log::warn!("Selector position {} is outside the soft limits", to);
+            return Err(Error::OutsideTravel);
         }
+        lanes::check_selector_move(
+            &self.config,
+            &self.lanes,
+            self.current_filament,
+            self.hub_occupied(),
+            self.current_position,
+            to,
+        )?;
+        self.current_position = None;
+        self.persist();
+        Ok(())
+    }
+
+    fn end_selector_move(&mut self, position: Steps) {
+        self.current_position = Some(position);
+        self.persist();
+    }
+
+    fn endstop_triggered(&self) -> bool {
+        self.selector_endstop
+            .as_ref()
+            .is_some_and(|endstop| endstop.is_high())
+    }
+
+    fn rehome_due(&self) -> bool {
+        self.odometer.idle
+            || self
+                .config
+                .rehome_after_changes
+                .is_some_and(|changes| self.odometer.changes >= changes)
     }
 
-    pub async fn extrude(&mut self, mm: f32, mm_per_min: f32) {
-        let steps = mm_to_steps(mm);
-        let speed_mm_per_sec = mm_per_min / 60.0;
-        let step_duration_us =
-            (1.0 / (speed_mm_per_sec * EXTRUDER_STEPS_PER_MM as f32) * 1_000_000.0) as u64;
-        let step_duration = Duration::from_micros(step_duration_us);
+    // Idle long enough since the selector last moved.
+    fn idle_rehome_due(&self, idle_since: Instant) -> bool {
+        self.config
+            .rehome_when_idle
+            .is_some_and(|idle| idle_since.elapsed() >= idle)
+            && self.odometer.moves > 0
+            && !self.odometer.idle
+            && self.current_position.is_some()
+            && self.calibration.is_none()
+    }
 
-        self.move_stepper_extruder(steps, true, step_duration).await; // Assuming forward extrusion
+    // Homes right away with every lane parked. With a filament loaded,
+    // which may be printing, the next change homes between unloading and
+    // selecting instead.
+    async fn rehome_when_idle(&mut self) {
+        if self.current_filament.is_none()
+            && self.lanes.iter().all(|&state| state == LaneState::Parked)
+        {
+            log::info!("Idle, homing again");
+            self.execute(Command::Home).await;
+        } else {
+            log::info!("Idle, homing again at the next change");
+            self.odometer.idle = true;
+        }
     }
 
-    pub async fn retract(&mut self, mm: f32, mm_per_min: f32) {
-        let steps = mm_to_steps(mm);
-        let speed_mm_per_sec = mm_per_min / 60.0;
-        let step_duration_us =
-            (1.0 / (speed_mm_per_sec * EXTRUDER_STEPS_PER_MM as f32) * 1_000_000.0) as u64;
-        let step_duration = Duration::from_micros(step_duration_us);
+    async fn wait(&mut self, duration: Duration) -> Result<(), Error> {
+        motion::wait(&mut self.watchdog, duration).await
+    }
 
-        self.move_stepper_extruder(steps, false, step_duration)
-            .await; // Reverse direction for retraction
+    // Pads whatever started at `start` to take `duration`, so the printer
+    // can wait a fixed time for it.
+    async fn wait_until(&mut self, start: Instant, duration: Duration) -> Result<(), Error> {
+        let elapsed = start.elapsed();
+        if elapsed < duration {
+            log::debug!(
+                "Adding delay of {:?} to equalize movement time",
+                duration - elapsed
+            );
+            self.wait(duration - elapsed).await?;
+        }
+        Ok(())
     }
 
-    async fn home(&mut self) {
+    async fn home(&mut self) -> Result<(), Error> {
         let start_time = Instant::now();
-        log::info!("Homing starting");
+        let expected = match self.current_position {
+            Some(position) => timing::home(&self.config, position, self.current_filament),
+            None => timing::home(&self.config, Steps(0), None),
+        };
+        log::info!(
+            "Homing starting, expected to take {}ms",
+            expected.total().as_millis()
+        );
+        events::publish(Event::HomingStarted);
 
-        self.change_filament(None).await;
+        // Lanes never tracked before are assumed clear, as they always were.
+        for filament in 0..self.lanes.len() {
+            if self.lanes[filament] == LaneState::Unknown {
+                log::warn!("Lane {} state unknown, assuming parked", filament);
+                self.set_lane(filament, LaneState::Parked);
+            }
+        }
+
+        if self.current_position.is_some() {
+            self.change_filament(None).await?;
+        } else if let Some(filament) = self.current_filament {
+            self.recover_loaded(filament).await?;
+        }
 
         // disable steppers to save power
         self.stepper_b_extruder_en.set_high();
         self.stepper_a_selector_en.set_high();
-        // move servo back to resting position
-        self.pwm_pin.set_timestamp(SERVO_RESTING_POSITION);
-        Timer::after(Duration::from_millis(2_000)).await;
-
-        let homing_steps_half = HOMING_STEPS / 2;
-
-        // First move: Normal speed
-        self.move_stepper_selector(homing_steps_half, false, Some(HOMING_STEP_SPEED))
-            .await;
-        // Second move: Half speed
-        self.move_stepper_selector(homing_steps_half, false, Some(HOMING_STEP_SPEED * 2))
-            .await;
+        // move the cutter blade out of the way
+        self.cutter.open(&mut self.watchdog).await?;
+        self.wait(timing::settle_cutter(&self.config)).await?;
 
         self.current_filament = None;
-        self.current_position = 0;
+        self.home_selector().await?;
 
         // disable steppers to save power
         self.stepper_b_extruder_en.set_high();
         self.stepper_a_selector_en.set_high();
 
         let duration = start_time.elapsed();
-        log::info!("Homing completed in {}ms", duration.as_millis());
+        log::info!(
+            "Homing completed in {}ms, expected {}ms",
+            duration.as_millis(),
+            expected.total().as_millis()
+        );
+        events::publish(Event::HomingCompleted);
+        Ok(())
+    }
+
+    // Drives the selector to its home position, to the endstop if there is
+    // one and otherwise against the end of its travel.
+    async fn home_selector(&mut self) -> Result<(), Error> {
+        let expected_position = self.current_position;
+        log::info!(
+            "Homing selector after {} changes, {} moves, {} steps of travel",
+            self.odometer.changes,
+            self.odometer.moves,
+            self.odometer.travel.0
+        );
+        self.begin_selector_move(Steps(0))?;
+        self.drive_home(expected_position).await
+    }
+
+    // Homes the selector with `filament` still through it, then takes the
+    // selector back to it and unloads it as usual.
+    async fn recover_loaded(&mut self, filament: usize) -> Result<(), Error> {
+        if self.lanes[filament] == LaneState::Parked {
+            self.current_filament = None;
+            self.persist();
+            return Ok(());
+        }
+        log::warn!(
+            "Position unknown, homing with filament {} still loaded",
+            filament
+        );
+        self.begin_recovery_move(filament)?;
+        self.drive_home(None).await?;
+
+        let target_position = self.config.lane_position(filament);
+        self.begin_recovery_move(filament)?;
+        self.drive_selector(Steps(0), target_position).await?;
+        self.end_selector_move(target_position);
+        self.change_filament(None).await
+    }
+
+    // Like `begin_selector_move`, for the moves of `recover_loaded`.
+    fn begin_recovery_move(&mut self, filament: usize) -> Result<(), Error> {
+        lanes::check_recovery_move(&self.lanes, filament)?;
+        self.current_position = None;
+        self.persist();
+        Ok(())
+    }
+
+    async fn drive_home(&mut self, expected_position: Option<Steps>) -> Result<(), Error> {
+        if self.selector_endstop.is_some() {
+            let travelled = self
+                .drive_to_endstop()
+                .await?
+                .ok_or(Error::EndstopNotReached)?;
+            if let Some(expected_position) = expected_position {
+                self.report_drift(expected_position, travelled);
+            }
+        } else {
+            let homing_steps_half = self.config.homing_steps.half();
+            let homing_step_speed = self.config.homing_step_speed;
+            // First move: Normal speed
+            self.move_stepper_selector(homing_steps_half, false, Some(homing_step_speed))
+                .await?;
+            // Second move: Half speed
+            self.move_stepper_selector(homing_steps_half, false, Some(homing_step_speed * 2))
+                .await?;
+        }
+
+        self.selector_direction = Some(false);
+        self.odometer = Odometer::default();
+        self.end_selector_move(Steps(0));
+        Ok(())
     }
 
-    async fn move_to_resting_position(&mut self) {
+    // Moves the selector towards home until the endstop triggers, and
+    // returns how many steps that took. None if it didn't trigger within
+    // `homing_steps`.
+    async fn drive_to_endstop(&mut self) -> Result<Option<Steps>, Error> {
+        self.stepper_b_extruder_en.set_high();
+        self.stepper_a_selector_en.set_low();
+        self.stepper_a_selector_dir.set_low();
+
+        let speed = self.config.homing_step_speed;
+        for step in 0..self.config.homing_steps.0 {
+            if self.endstop_triggered() {
+                return Ok(Some(Steps(step)));
+            }
+            self.step_motor_a(speed).await?;
+        }
+        Ok(self.endstop_triggered().then_some(self.config.homing_steps))
+    }
+
+    fn report_drift(&mut self, expected_position: Steps, travelled: Steps) {
+        let drift = travelled.0 as i32 - expected_position.0 as i32;
+        if drift.unsigned_abs() > self.config.selector_drift_tolerance.0 {
+            log::warn!(
+                "Selector drifted {} steps, found at {} instead of {}",
+                drift,
+                travelled.0,
+                expected_position
+            );
+            events::publish(Event::SelectorDrift { steps: drift });
+        } else {
+            log::info!("Selector drift {} steps", drift);
+        }
+    }
+
+    // The lane that most often followed `filament` so far.
+    fn likely_next(&self, filament: usize) -> Option<usize> {
+        self.transitions[filament]
+            .iter()
+            .enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .max_by_key(|&(_, &count)| count)
+            .map(|(next, _)| next)
+    }
+
+    async fn move_to_park_position(&mut self) -> Result<(), Error> {
         if let Some(current_filament) = self.current_filament {
-            let target_position = FILAMENT_RESTING_POSITIONS[current_filament];
+            let next = self.likely_next(current_filament);
+            let target_position = self.config.park_position(current_filament, next);
             log::info!(
-                "Moving to resting position for filament {}",
-                current_filament
+                "Parking selector for filament {}, next likely {:?}",
+                current_filament,
+                next
             );
-            let (steps, direction) = if target_position > self.current_position {
-                (target_position - self.current_position, true)
-            } else {
-                (self.current_position - target_position, false)
-            };
-            self.move_stepper_selector(steps, direction, None).await;
-            self.current_position = target_position;
-            log::info!("Moved to resting position: {}", self.current_position);
+            self.move_selector(target_position).await?;
+            log::info!("Selector parked at position: {}", target_position);
         } else {
-            log::warn!("No current filament selected, cannot move to resting position");
+            log::warn!("No current filament selected, cannot park the selector");
+        }
+        Ok(())
+    }
+
+    async fn move_selector(&mut self, target_position: Steps) -> Result<(), Error> {
+        let current_position = self.position()?;
+        self.begin_selector_move(target_position)?;
+        self.drive_selector(current_position, target_position)
+            .await?;
+        self.end_selector_move(target_position);
+        Ok(())
+    }
+
+    // Drives the selector from `from` to `to`, taking up the backlash of the
+    // drive the way `selector_backlash` says.
+    async fn drive_selector(&mut self, from: Steps, to: Steps) -> Result<(), Error> {
+        if from == to {
+            return Ok(());
         }
+        let forward = to > from;
+        let distance = from.abs_diff(to);
+        match self.config.selector_backlash {
+            Backlash::None => {
+                self.move_stepper_selector(distance, forward, None).await?;
+            }
+            Backlash::Compensate(slack) => {
+                let reversing = self.selector_direction.is_some_and(|last| last != forward);
+                let extra = if reversing { slack } else { Steps(0) };
+                self.move_stepper_selector(distance + extra, forward, None)
+                    .await?;
+            }
+            Backlash::FinalApproach(overshoot) if !forward => {
+                let overshoot = self.config.final_approach_overshoot(to, overshoot);
+                self.move_stepper_selector(distance + overshoot, false, None)
+                    .await?;
+                self.move_stepper_selector(overshoot, true, None).await?;
+            }
+            Backlash::FinalApproach(_) => {
+                self.move_stepper_selector(distance, true, None).await?;
+            }
+        }
+        self.odometer.moves += 1;
+        self.odometer.travel = self.odometer.travel.saturating_add(distance);
+        self.selector_direction = Some(match self.config.selector_backlash {
+            Backlash::FinalApproach(_) => true,
+            _ => forward,
+        });
+        Ok(())
+    }
+
+    // Moves the selector to `mm` from home, wherever the lanes are.
+    pub async fn move_selector_to(&mut self, mm: Millimeters) -> Result<(), Error> {
+        let target_position = self.config.selector_position(mm)?;
+        self.move_selector(target_position).await?;
+        log::info!("Selector at {}mm", mm.0);
+        Ok(())
     }
 
     async fn move_stepper_selector(
         &mut self,
-        steps: u32,
+        steps: Steps,
         direction: bool,
-        speed: Option<Duration>,
-    ) {
+        speed: Option<StepInterval>,
+    ) -> Result<(), Error> {
+        debug_assert!(
+            self.current_position.is_none(),
+            "selector moved without begin_selector_move"
+        );
         self.stepper_b_extruder_en.set_high();
         self.stepper_a_selector_en.set_low();
         if direction {
@@ -197,14 +530,55 @@ impl<'a> FilamentChanger<'a> {
             self.stepper_a_selector_dir.set_low();
         }
 
-        let step_speed = speed.unwrap_or(SELECTOR_STEP_SPEED);
+        let step_speed = speed.unwrap_or(self.config.selector_step_speed);
+
+        for _ in 0..steps.0 {
+            self.step_motor_a(step_speed).await?;
+        }
+        Ok(())
+    }
+
+    async fn move_stepper_extruder(
+        &mut self,
+        steps: Steps,
+        direction: bool,
+        speed: StepInterval,
+    ) -> Result<(), Error> {
+        self.move_stepper_extruder_ramped(steps, direction, speed, StepRamp::NONE)
+            .await
+    }
+
+    async fn move_stepper_extruder_ramped(
+        &mut self,
+        steps: Steps,
+        direction: bool,
+        speed: StepInterval,
+        ramp: StepRamp,
+    ) -> Result<(), Error> {
+        self.stepper_b_extruder_en.set_low();
+        if direction {
+            self.stepper_b_extruder_dir.set_high();
+        } else {
+            self.stepper_b_extruder_dir.set_low();
+        }
 
-        for _ in 0..steps {
-            self.step_motor_a(step_speed).await;
+        for step in 0..steps.0 {
+            self.step_motor_b_extruder(ramp.interval(step, steps, speed))
+                .await?;
         }
+        self.stepper_b_extruder_en.set_high();
+        Ok(())
     }
 
-    async fn move_stepper_extruder(&mut self, steps: u32, direction: bool, speed: Duration) {
+    // Feeds the filament towards the printer until `triggered`, and returns
+    // how many steps that took. None if it didn't trigger within
+    // `max_steps`, which were all fed.
+    async fn feed_until(
+        &mut self,
+        triggered: fn(&Self) -> bool,
+        max_steps: Steps,
+        direction: bool,
+    ) -> Result<Option<Steps>, Error> {
         self.stepper_b_extruder_en.set_low();
         if direction {
             self.stepper_b_extruder_dir.set_high();
@@ -212,93 +586,209 @@ impl<'a> FilamentChanger<'a> {
             self.stepper_b_extruder_dir.set_low();
         }
 
-        for _ in 0..steps {
-            self.step_motor_b_extruder(speed).await;
+        let speed = self.config.calibration_step_speed;
+        let mut fed = None;
+        for step in 0..max_steps.0 {
+            if triggered(self) {
+                fed = Some(Steps(step));
+                break;
+            }
+            self.step_motor_b_extruder(speed).await?;
         }
         self.stepper_b_extruder_en.set_high();
+        Ok(fed)
     }
 
-    async fn unload_filament(&mut self) {
-        self.unload_filament_by(UNLOAD_STEPS, EXTRUDER_STEP_SPEED)
-            .await;
+    async fn unload_filament(&mut self, filament: usize) -> Result<(), Error> {
+        let profile = *self.config.profile(filament);
+        self.unload_filament_by(
+            self.config.unload_steps_for(filament),
+            profile.unload_step_speed,
+            profile.ramp,
+        )
+        .await
     }
 
-    async fn unload_filament_by(&mut self, steps: u32, speed: Duration) {
+    async fn unload_filament_by(
+        &mut self,
+        steps: Steps,
+        speed: StepInterval,
+        ramp: StepRamp,
+    ) -> Result<(), Error> {
         if let Some(current_filament) = self.current_filament {
-            self.move_stepper_extruder(steps, current_filament < 2, speed)
-                .await;
+            let direction = self.config.unload_direction(current_filament);
+            self.move_stepper_extruder_ramped(steps, direction, speed, ramp)
+                .await?;
         }
+        Ok(())
     }
 
-    async fn cut_filament(&mut self) {
+    async fn cut_filament(&mut self, filament: usize) -> Result<(), Error> {
         let start_time = Instant::now();
+        let sequence = *self.config.cut_sequence(filament);
+        log::info!(
+            "Cutting filament {} as {}",
+            filament,
+            self.config.lane_info[filament].material.name()
+        );
 
         // disable steppers to save power
         self.stepper_b_extruder_en.set_high();
         self.stepper_a_selector_en.set_high();
 
-        // move servo to cut filament
-        self.pwm_pin.set_timestamp(SERVO_CUTTING_POSITION);
-        Timer::after(Duration::from_millis(750)).await;
-        self.pwm_pin.set_timestamp(SERVO_RESTING_POSITION);
-
-        Timer::after(Duration::from_millis(500)).await;
-        self.pwm_pin.set_timestamp(SERVO_CUTTING_POSITION);
-        Timer::after(Duration::from_millis(750)).await;
-        self.pwm_pin.set_timestamp(SERVO_RESTING_POSITION);
-
-        Timer::after(Duration::from_millis(500)).await;
-        self.pwm_pin.set_timestamp(SERVO_CUTTING_POSITION);
-        Timer::after(Duration::from_millis(750)).await;
-        self.pwm_pin.set_timestamp(SERVO_RESTING_POSITION);
-        // move servo back to resting position
+        for (index, stroke) in sequence.strokes.iter().enumerate() {
+            if index > 0 {
+                self.wait(sequence.release_dwell).await?;
+                if sequence.wiggle_steps.0 > 0 {
+                    let speed = self.config.extruder_step_speed;
+                    let direction = self.config.load_direction(filament);
+                    self.move_stepper_extruder(sequence.wiggle_steps, !direction, speed)
+                        .await?;
+                    self.move_stepper_extruder(sequence.wiggle_steps, direction, speed)
+                        .await?;
+                }
+            }
+            self.cutter
+                .stroke(&mut self.watchdog, filament, stroke)
+                .await?;
+        }
+        self.cutter.open(&mut self.watchdog).await?;
 
         let duration = start_time.elapsed();
-        log::info!("Cut completed in {}ms", duration.as_millis());
+        log::info!(
+            "Cut completed in {}ms, expected {}ms",
+            duration.as_millis(),
+            timing::cut(&self.config, &sequence).as_millis()
+        );
+        Ok(())
     }
 
-    async fn load_filament(&mut self) {
+    async fn load_filament(&mut self) -> Result<(), Error> {
         let start_time = Instant::now();
         if let Some(current_filament) = self.current_filament {
-            let direction = current_filament >= 2;
+            let direction = self.config.load_direction(current_filament);
+            let profile = *self.config.profile(current_filament);
+            log::info!(
+                "Loading filament {} as {}",
+                current_filament,
+                self.config.lane_info[current_filament].material.name()
+            );
 
             // First section - normal speed
-            self.move_stepper_extruder(FAST_LOAD_STEPS, direction, EXTRUDER_FAST_LOAD_STEP_SPEED)
-                .await;
+            self.move_stepper_extruder_ramped(
+                self.config.fast_load_steps_for(current_filament),
+                direction,
+                profile.fast_load_step_speed,
+                profile.ramp,
+            )
+            .await?;
 
             // Second section - slow speed
-            self.move_stepper_extruder(SLOW_LOAD_STEPS, direction, EXTRUDER_SLOW_LOAD_STEP_SPEED)
-                .await;
+            self.move_stepper_extruder_ramped(
+                self.config.slow_load_steps_for(current_filament),
+                direction,
+                profile.slow_load_step_speed,
+                profile.ramp,
+            )
+            .await?;
+
+            let duration = start_time.elapsed();
+            log::info!(
+                "load_filament in {}ms, expected {}ms",
+                duration.as_millis(),
+                timing::load(&self.config, current_filament).as_millis()
+            );
         }
-        let duration = start_time.elapsed();
-        log::info!("load_filament in {}ms", duration.as_millis());
+        Ok(())
+    }
+
+    // Unloads the filament the selector is engaged with, then checks it
+    // actually cleared the hub.
+    async fn unload_to_parked(&mut self, filament: usize) -> Result<(), Error> {
+        // // Unload a little bit of filament to reduce the wipe tower size/time
+        // self.unload_filament_by(self.config.mm_to_steps(10f32), self.config.extruder_step_speed)
+        //     .await;
+        self.unload_filament(filament).await?;
+        if self.hub_occupied() {
+            log::warn!(
+                "Filament {} still at the hub after unloading, is unload_steps too short?",
+                filament
+            );
+            return Err(Error::HubOccupied);
+        }
+        self.set_lane(filament, LaneState::Parked);
+        events::publish(Event::Unloaded { filament });
+        Ok(())
+    }
+
+    // Rams the filament and moves it through the cooling zone, so it comes
+    // out of the hotend with a clean tip instead of being cut.
+    async fn form_tip(&mut self, filament: usize) -> Result<(), Error> {
+        let start_time = Instant::now();
+        let sequence = self.config.tip_forming;
+        log::info!("Forming tip of filament {}", filament);
+
+        for tip_move in sequence.ramming {
+            if tip_move.mm.0 >= 0.0 {
+                self.extrude(tip_move.mm, tip_move.mm_per_min).await?;
+            } else {
+                self.retract(-tip_move.mm, tip_move.mm_per_min).await?;
+            }
+        }
+        for _ in 0..sequence.cooling_moves {
+            self.extrude(sequence.cooling_mm, sequence.cooling_mm_per_min)
+                .await?;
+            self.retract(sequence.cooling_mm, sequence.cooling_mm_per_min)
+                .await?;
+        }
+
+        log::info!(
+            "Tip formed in {}ms, expected {}ms",
+            start_time.elapsed().as_millis(),
+            timing::tip_forming(&self.config).as_millis()
+        );
+        Ok(())
     }
 
-    async fn change_filament(&mut self, new_filament: Option<usize>) {
+    async fn change_filament(&mut self, new_filament: Option<usize>) -> Result<(), Error> {
+        let current_position = self.position()?;
         if new_filament == self.current_filament {
             log::info!("Filament '{:?}' already selected", new_filament);
-            return;
+            return Ok(());
         }
 
         let start_time = Instant::now();
-        log::info!("Selecting filament {:?}", new_filament);
+        let expected = timing::change(
+            &self.config,
+            current_position,
+            self.current_filament,
+            new_filament,
+            new_filament.is_some() && self.rehome_due(),
+            new_filament.and_then(|filament| self.likely_next(filament)),
+        );
+        if let (Some(from), Some(to)) = (self.current_filament, new_filament) {
+            self.transitions[from][to] = self.transitions[from][to].saturating_add(1);
+        }
+        log::info!(
+            "Selecting filament {:?}, expected to take {}ms",
+            new_filament,
+            expected.total().as_millis()
+        );
         if let Some(current_filament_id) = self.current_filament {
-            self.cut_filament().await;
-            self.move_to_filament(current_filament_id).await;
-            // // Unload a little bit of filament to reduce the wipe tower size/time
-            // self.unload_filament_by(mm_to_steps(10f32), EXTRUDER_STEP_SPEED)
-            //     .await;
-            self.unload_filament().await;
+            let toolchange = self.config.toolchange;
+            toolchange.unload(self, current_filament_id).await?;
         }
 
         if let Some(target_filament_id) = new_filament {
+            // every lane is parked now, so the selector can go home
+            if self.rehome_due() {
+                self.home_selector().await?;
+            }
             let start_time_for_change = Instant::now();
-            self.move_to_filament(target_filament_id).await;
-            // Calculate the maximum possible steps (distance between furthest positions)
-            let max_steps = FILAMENT_POSITIONS[3];
+            self.move_to_filament(target_filament_id).await?;
+            self.odometer.changes += 1;
             // Calculate and add delay to make all movements take the same time
-            let step_time = SELECTOR_STEP_SPEED * 2; // Total time per step (high + low state)
-            let max_movement_time = step_time * max_steps;
+            let max_movement_time = timing::equalized_selection(&self.config);
 
             log::info!(
                 "Took {}ms, max: {}ms",
@@ -306,128 +796,544 @@ impl<'a> FilamentChanger<'a> {
                 max_movement_time.as_millis()
             );
 
-            let elapsed_time = start_time_for_change.elapsed();
-            let remaining_time = if elapsed_time < max_movement_time {
-                max_movement_time - elapsed_time
-            } else {
-                Duration::from_micros(0)
-            };
-
-            if remaining_time > Duration::from_micros(0) {
-                log::debug!(
-                    "Adding delay of {:?} to equalize movement time",
-                    remaining_time
-                );
-                Timer::after(remaining_time).await;
-            }
+            self.wait_until(start_time_for_change, max_movement_time)
+                .await?;
             log::info!(
                 "time normalized at {}ms",
                 start_time_for_change.elapsed().as_millis()
             );
+            events::publish(Event::LaneSelected {
+                filament: target_filament_id,
+            });
 
-            self.load_filament().await;
+            self.set_lane(target_filament_id, LaneState::InHub);
+            self.load_filament().await?;
+            self.set_lane(target_filament_id, LaneState::AtNozzle);
+            events::publish(Event::Loaded {
+                filament: target_filament_id,
+            });
             let duration = start_time.elapsed();
-            log::info!("Filament changed and loaded in {}ms", duration.as_millis());
+            log::info!(
+                "Filament changed and loaded in {}ms, expected {}ms",
+                duration.as_millis(),
+                (expected.unload_phase() + expected.load_phase()).as_millis()
+            );
 
-            self.move_to_resting_position().await;
+            self.move_to_park_position().await?;
         } else {
             self.current_filament = None;
+            self.persist();
         }
         log::info!("Filament {:?} selected", new_filament);
+        Ok(())
     }
 
-    async fn move_to_filament(&mut self, filament: usize) {
-        let target_position = FILAMENT_POSITIONS[filament];
+    async fn move_to_filament(&mut self, filament: usize) -> Result<(), Error> {
+        let target_position = self.config.lane_position(filament);
+        let current_position = self.position()?;
         log::info!(
             "Moving to filament {}, target position: {}",
             filament,
             target_position
         );
 
-        let (steps, direction) = if target_position > self.current_position {
+        let (steps, direction) = if target_position > current_position {
             log::debug!(
                 "Moving forward {} steps",
-                target_position - self.current_position
+                target_position - current_position
             );
-            (target_position - self.current_position, true)
+            (target_position - current_position, true)
         } else {
             log::debug!(
                 "Moving backward {} steps",
-                self.current_position - target_position
+                current_position - target_position
             );
-            (self.current_position - target_position, false)
+            (current_position - target_position, false)
         };
 
         log::debug!(
             "Current position: {}, Moving {} steps in direction: {}",
-            self.current_position,
+            current_position,
             steps,
             direction
         );
-        self.move_stepper_selector(steps, direction, None).await;
-        self.current_position = target_position;
+        self.begin_selector_move(target_position)?;
+        self.drive_selector(current_position, target_position)
+            .await?;
         self.current_filament = Some(filament);
+        self.end_selector_move(target_position);
         log::info!(
             "Moved to filament {}, new position: {}",
             filament,
-            self.current_position,
+            target_position,
+        );
+        Ok(())
+    }
+
+    // Measures the bowden of `filament` by feeding it from its parked
+    // position until the hub sensor triggers, then on to the toolhead sensor
+    // if there is one, and parks it again. Without a toolhead sensor the hub
+    // to extruder length is kept, with only a toolhead sensor see
+    // `calibrate_bowden_to_toolhead`.
+    async fn calibrate_bowden(&mut self, filament: usize) -> Result<(), Error> {
+        if self.hub_sensor.is_none() {
+            if self.toolhead_sensor.is_some() {
+                return self.calibrate_bowden_to_toolhead(filament).await;
+            }
+            return Err(Error::NoSensor);
+        }
+        self.change_filament(None).await?;
+        log::info!("Calibrating bowden of filament {}", filament);
+
+        self.move_to_filament(filament).await?;
+        self.set_lane(filament, LaneState::InHub);
+        let direction = self.config.load_direction(filament);
+        let steps_per_mm = self.config.extruder_steps_per_mm;
+        let max_steps = steps_per_mm
+            .steps(self.config.calibration_max_mm)
+            .ok_or(Error::InvalidLength)?;
+        let to_hub = self
+            .feed_until(Self::hub_occupied, max_steps, direction)
+            .await?;
+        let toolhead_phase = to_hub.is_some() && self.toolhead_sensor.is_some();
+        let to_extruder = if toolhead_phase {
+            self.feed_until(Self::toolhead_triggered, max_steps, direction)
+                .await?
+        } else {
+            None
+        };
+
+        // a sensor that didn't trigger had all of max_steps fed
+        let fed = to_hub.unwrap_or(max_steps)
+            + match (toolhead_phase, to_extruder) {
+                (true, None) => max_steps,
+                (_, to_extruder) => to_extruder.unwrap_or_default(),
+            };
+        self.retract_calibration(filament, fed).await?;
+
+        let Some(to_hub) = to_hub else {
+            return Err(Error::SensorNotReached { filament });
+        };
+        if toolhead_phase && to_extruder.is_none() {
+            return Err(Error::SensorNotReached { filament });
+        }
+        let hub_to_extruder_mm = match to_extruder {
+            Some(steps) => steps_per_mm.mm(steps),
+            None => steps_per_mm.mm(self.config.slow_load_steps_for(filament)),
+        };
+        let lengths = BowdenLengths {
+            selector_to_hub_mm: steps_per_mm.mm(to_hub),
+            hub_to_extruder_mm,
+        };
+        log::info!("Filament {} bowden: {:?}", filament, lengths);
+        self.config.lane_geometry[filament].bowden = Some(lengths);
+        self.settings
+            .update(|saved| saved.bowden[filament] = Some(lengths));
+        Ok(())
+    }
+
+    // Measures the whole way to the toolhead sensor. Where the hub is can't
+    // be told, so the slow phase keeps its length and the fast phase covers
+    // the rest.
+    async fn calibrate_bowden_to_toolhead(&mut self, filament: usize) -> Result<(), Error> {
+        self.change_filament(None).await?;
+        log::info!(
+            "Calibrating bowden of filament {} to the toolhead",
+            filament
+        );
+
+        self.move_to_filament(filament).await?;
+        self.set_lane(filament, LaneState::InHub);
+        let direction = self.config.load_direction(filament);
+        let steps_per_mm = self.config.extruder_steps_per_mm;
+        let max_steps = steps_per_mm
+            .steps(self.config.calibration_max_mm)
+            .ok_or(Error::InvalidLength)?;
+        let to_extruder = self
+            .feed_until(Self::toolhead_triggered, max_steps, direction)
+            .await?;
+        self.retract_calibration(filament, to_extruder.unwrap_or(max_steps))
+            .await?;
+
+        let Some(to_extruder) = to_extruder else {
+            return Err(Error::SensorNotReached { filament });
+        };
+        let slow_steps = self.config.slow_load_steps_for(filament).min(to_extruder);
+        let lengths = BowdenLengths {
+            selector_to_hub_mm: steps_per_mm.mm(to_extruder - slow_steps),
+            hub_to_extruder_mm: steps_per_mm.mm(slow_steps),
+        };
+        log::info!("Filament {} bowden: {:?}", filament, lengths);
+        self.config.lane_geometry[filament].bowden = Some(lengths);
+        self.settings
+            .update(|saved| saved.bowden[filament] = Some(lengths));
+        Ok(())
+    }
+
+    // Moves the selector to `filament` with nothing loaded, so its position
+    // can be adjusted with `jog` and stored with `finish_selector_calibration`.
+    async fn calibrate_selector(&mut self, filament: usize) -> Result<(), Error> {
+        self.change_filament(None).await?;
+        self.move_to_filament(filament).await?;
+        self.current_filament = None;
+        self.persist();
+        self.calibration = Some(Calibration::Selector(filament));
+        log::info!(
+            "Adjusting selector position of filament {}, currently {}",
+            filament,
+            self.config.lane_position(filament)
+        );
+        Ok(())
+    }
+
+    async fn jog_selector(&mut self, steps: i32) -> Result<(), Error> {
+        let Some(Calibration::Selector(_)) = self.calibration else {
+            return Err(Error::NotCalibrating);
+        };
+        let target_position = self
+            .position()?
+            .checked_add_signed(steps)
+            .ok_or(Error::OutsideTravel)?;
+        self.move_selector(target_position).await?;
+        log::info!(
+            "Selector at {} ({}mm)",
+            target_position,
+            self.config.selector_position_mm(target_position).0
+        );
+        Ok(())
+    }
+
+    fn finish_selector_calibration(&mut self) -> Result<(), Error> {
+        let Some(Calibration::Selector(filament)) = self.calibration else {
+            return Err(Error::NotCalibrating);
+        };
+        let position = self.position()?;
+        log::info!(
+            "Filament {} selector position {} -> {}",
+            filament,
+            self.config.lane_position(filament),
+            position
+        );
+        self.config.lane_geometry[filament].selector_position = position;
+        self.calibration = None;
+        self.settings
+            .update(|saved| saved.filament_positions[filament] = Some(position));
+        Ok(())
+    }
+
+    // Feeds `extruder_calibration_mm` of `filament` and leaves it there, for
+    // the user to measure how much actually came out.
+    async fn calibrate_extruder(&mut self, filament: usize) -> Result<(), Error> {
+        self.change_filament(None).await?;
+        self.move_to_filament(filament).await?;
+        self.set_lane(filament, LaneState::InHub);
+        let steps = self
+            .config
+            .extruder_steps_per_mm
+            .steps(self.config.extruder_calibration_mm)
+            .ok_or(Error::InvalidLength)?;
+        let direction = self.config.load_direction(filament);
+        self.move_stepper_extruder(steps, direction, self.config.calibration_step_speed)
+            .await?;
+        self.calibration = Some(Calibration::Extruder { filament, steps });
+        log::info!(
+            "Fed {}mm of filament {}, enter the measured length",
+            self.config.extruder_calibration_mm.0,
+            filament
+        );
+        Ok(())
+    }
+
+    async fn finish_extruder_calibration(&mut self, measured_mm: Millimeters) -> Result<(), Error> {
+        let Some(Calibration::Extruder { filament, steps }) = self.calibration else {
+            return Err(Error::NotCalibrating);
+        };
+        // Anything this far off is a typo rather than a worn gear.
+        let commanded_mm = self.config.extruder_calibration_mm.0;
+        if !(commanded_mm * 0.5..=commanded_mm * 1.5).contains(&measured_mm.0) {
+            return Err(Error::InvalidMeasurement);
+        }
+        self.calibration = None;
+        self.retract_calibration(filament, steps).await?;
+
+        let old_steps_per_mm = self.config.extruder_steps_per_mm;
+        let steps_per_mm = StepsPerMm(steps.0 as f32 / measured_mm.0);
+        log::info!(
+            "Extruder steps/mm {} -> {}",
+            old_steps_per_mm.0,
+            steps_per_mm.0
         );
+        // The calibrated bowden lengths were counted in steps, keep them the
+        // same number of steps.
+        let saved = self.settings.update(|saved| {
+            saved.extruder_steps_per_mm = Some(steps_per_mm.0);
+            for lengths in saved.bowden.iter_mut().flatten() {
+                lengths.selector_to_hub_mm = steps_per_mm.mm(old_steps_per_mm
+                    .steps(lengths.selector_to_hub_mm)
+                    .unwrap_or_default());
+                lengths.hub_to_extruder_mm = steps_per_mm.mm(old_steps_per_mm
+                    .steps(lengths.hub_to_extruder_mm)
+                    .unwrap_or_default());
+            }
+        });
+        saved.apply(&mut self.config);
+        Ok(())
+    }
+
+    // Pulls back the `steps` a calibration fed of `filament` and parks it.
+    async fn retract_calibration(&mut self, filament: usize, steps: Steps) -> Result<(), Error> {
+        self.unload_filament_by(steps, self.config.extruder_step_speed, StepRamp::NONE)
+            .await?;
+        self.current_filament = None;
+        self.persist();
+        if self.hub_occupied() {
+            log::warn!("Filament {} still at the hub after calibrating", filament);
+        } else {
+            self.set_lane(filament, LaneState::Parked);
+        }
+        Ok(())
     }
 
-    async fn step_motor_a(&mut self, speed: Duration) {
+    // A calibration still waiting for input is cancelled by any other
+    // command, and a fed filament is parked again first.
+    async fn abandon_calibration(&mut self) -> Result<(), Error> {
+        match self.calibration.take() {
+            Some(Calibration::Selector(filament)) => {
+                log::warn!("Selector calibration of filament {} abandoned", filament);
+                Ok(())
+            }
+            Some(Calibration::Extruder { filament, steps }) => {
+                log::warn!("Extruder calibration with filament {} abandoned", filament);
+                self.retract_calibration(filament, steps).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn step_motor_a(&mut self, speed: StepInterval) -> Result<(), Error> {
         self.stepper_a_selector_step.set_high();
-        Timer::after(speed).await;
+        self.wait(speed.0).await?;
         self.stepper_a_selector_step.set_low();
-        Timer::after(speed).await;
+        self.wait(speed.0).await
     }
 
-    async fn step_motor_b_extruder(&mut self, speed: Duration) {
+    async fn step_motor_b_extruder(&mut self, speed: StepInterval) -> Result<(), Error> {
         self.stepper_b_extruder_step.set_high();
-        Timer::after(speed).await;
+        self.wait(speed.0).await?;
         self.stepper_b_extruder_step.set_low();
-        Timer::after(speed).await;
+        self.wait(speed.0).await
+    }
+
+    // Leaves the hardware safe after a failed command. An interrupted move
+    // means the selector could be anywhere, so it has to be homed again.
+    fn stop(&mut self, error: Error) {
+        self.stepper_b_extruder_en.set_high();
+        self.stepper_a_selector_en.set_high();
+        self.cutter.release();
+
+        if error == Error::Aborted {
+            self.current_position = None;
+            self.persist();
+            log::warn!("Position unknown, homing required");
+        }
+        events::publish(Event::Error(error));
+    }
+
+    // Refused for the loaded lane, whose selector could then no longer move
+    // back to it.
+    fn set_selector_park(&mut self, filament: usize, park: SelectorPark) -> Result<(), Error> {
+        if self.current_filament == Some(filament) {
+            return Err(Error::LaneEngaged { filament });
+        }
+        if let SelectorPark::Position(position) = park {
+            if !self.config.is_within_travel(position) {
+                return Err(Error::OutsideTravel);
+            }
+        }
+        log::info!("Selector parks at {:?} for lane {}", park, filament);
+        self.config.lane_geometry[filament].park = park;
+        self.settings
+            .update(|saved| saved.selector_park[filament] = Some(park));
+        Ok(())
+    }
+
+    fn set_lane_info(&mut self, filament: usize, info: LaneInfo) -> Result<(), Error> {
+        if !info.is_valid() {
+            return Err(Error::InvalidLaneInfo);
+        }
+        log::info!("Lane {}: {:?}", filament, info);
+        self.config.lane_info[filament] = info;
+        status::set_lane_info(self.config.lane_info);
+        self.settings
+            .update(|saved| saved.lane_info[filament] = Some(info));
+        Ok(())
+    }
+
+    fn mark_parked(&mut self, filament: usize) {
+        log::info!("Lane {} cleared by hand", filament);
+        if self.current_filament == Some(filament) {
+            self.current_filament = None;
+        }
+        self.persist();
+        self.set_lane(filament, LaneState::Parked);
+    }
+
+    async fn execute(&mut self, command: Command) {
+        command::clear_abort();
+        let result = self.dispatch(command).await;
+        if let Err(error) = result {
+            log::error!("{:?} failed: {:?}", command, error);
+            self.stop(error);
+        }
+    }
+
+    async fn dispatch(&mut self, command: Command) -> Result<(), Error> {
+        if !matches!(
+            command,
+            Command::JogSelector(_)
+                | Command::FinishSelectorCalibration
+                | Command::FinishExtruderCalibration(_)
+        ) {
+            self.abandon_calibration().await?;
+        }
+
+        match command {
+            Command::Home => self.home().await,
+            Command::Select(filament) => self.change_filament(Some(filament)).await,
+            Command::MoveSelector(mm) => self.move_selector_to(mm).await,
+            Command::MarkParked(filament) => {
+                self.mark_parked(filament);
+                Ok(())
+            }
+            Command::SetToolchange(toolchange) => {
+                log::info!("Toolchange is now {}", toolchange.name());
+                self.config.toolchange = toolchange;
+                self.settings
+                    .update(|saved| saved.toolchange = Some(toolchange));
+                // the printer's waits and pre-cut retract depend on it
+                #[cfg(feature = "slicer-profile")]
+                log::info!("Reboot to print the matching slicer profile");
+                Ok(())
+            }
+            Command::SetBacklash(backlash) => {
+                log::info!("Selector backlash is now {:?}", backlash);
+                self.config.selector_backlash = backlash;
+                // the slack is unknown until the next move
+                self.selector_direction = None;
+                self.settings
+                    .update(|saved| saved.selector_backlash = Some(backlash));
+                Ok(())
+            }
+            Command::SetSelectorPark(filament, park) => self.set_selector_park(filament, park),
+            Command::SetMaterial(filament, material) => {
+                log::info!("Lane {} is now {}", filament, material.name());
+                let mut info = self.config.lane_info[filament];
+                info.material = material;
+                self.set_lane_info(filament, info)?;
+                // the printer's waits depend on it
+                #[cfg(feature = "slicer-profile")]
+                log::info!("Reboot to print the matching slicer profile");
+                Ok(())
+            }
+            Command::UpdateLaneInfo(filament, update) => {
+                let mut info = self.config.lane_info[filament];
+                update.apply(&mut info);
+                self.set_lane_info(filament, info)
+            }
+            Command::CalibrateBowden(filament) => self.calibrate_bowden(filament).await,
+            Command::CalibrateSelector(filament) => self.calibrate_selector(filament).await,
+            Command::JogSelector(steps) => self.jog_selector(steps).await,
+            Command::FinishSelectorCalibration => self.finish_selector_calibration(),
+            Command::CalibrateExtruder(filament) => self.calibrate_extruder(filament).await,
+            Command::FinishExtruderCalibration(measured_mm) => {
+                self.finish_extruder_calibration(measured_mm).await
+            }
+        }
+    }
+
+    // Picks up the state journaled before the last reset, or homes when it
+    // isn't known or resuming is disabled.
+    async fn restore(&mut self) {
+        match self.journaled.take() {
+            Some(snapshot) => {
+                log::info!(
+                    "Before reset: filament {:?} at position {:?}, lanes {:?}",
+                    snapshot.current_filament,
+                    snapshot.current_position,
+                    snapshot.lanes
+                );
+                // Where the filaments are doesn't depend on the selector, so
+                // the lanes are always kept and homing unloads the loaded one.
+                self.current_filament = snapshot.current_filament;
+                self.lanes = snapshot.lanes;
+                if self.config.resume_after_reset && snapshot.current_position.is_some() {
+                    log::info!("Resuming without homing");
+                    self.current_position = snapshot.current_position;
+                }
+                self.persist();
+                if self.current_position.is_some() {
+                    return;
+                }
+            }
+            None => log::info!("No journaled state found"),
+        }
+
+        self.execute(Command::Home).await;
     }
 
     pub async fn run(&mut self) {
         log::info!("Starting filament changer");
-        self.home().await;
+        log::info!(
+            "Worst case timings: change {}ms, home {}ms",
+            timing::worst_case_change(&self.config).total().as_millis(),
+            timing::worst_case_home(&self.config).total().as_millis()
+        );
+        status::set_lane_info(self.config.lane_info);
+        self.restore().await;
 
+        let mut idle_since = Instant::now();
         loop {
-            if self.endswitch.is_high() {
-                log::debug!("Endswitch triggered");
-                self.led.set_low();
-                let start = embassy_time::Instant::now();
-                let mut last_toggle = start;
-                while self.endswitch.is_high() {
-                    let now = embassy_time::Instant::now();
-                    if (now - last_toggle) >= Duration::from_millis(500) {
-                        self.led.toggle();
-                        last_toggle = now;
-                    }
-                    Timer::after(Duration::from_millis(10)).await;
+            match select(command::receive(), Timer::after(WATCHDOG_FEED_INTERVAL)).await {
+                Either::First(command) => {
+                    self.execute(command).await;
+                    idle_since = Instant::now();
                 }
-                let duration = start.elapsed();
-
-                if duration >= Duration::from_millis(2750) {
-                    log::info!("Homing command detected");
-                    self.home().await;
-                } else {
-                    let filament = match duration.as_millis() {
-                        250..=750 => 0,
-                        751..=1250 => 1,
-                        1251..=1750 => 2,
-                        1751..=2250 => 3, // Upper bound to distinguish from homing
-                        _ => {
-                            log::warn!("Unexpected duration: {} ms", duration.as_millis());
-                            continue;
-                        }
-                    };
-
-                    self.change_filament(Some(filament)).await;
+                Either::Second(()) => {
+                    self.watchdog.feed();
+                    if self.idle_rehome_due(idle_since) {
+                        self.rehome_when_idle().await;
+                        idle_since = Instant::now();
+                    }
                 }
             }
-
-            Timer::after(Duration::from_millis(25)).await;
         }
     }
 }
+
+impl ToolchangeContext for FilamentChanger<'_> {
+    fn config(&self) -> &Config {
+        FilamentChanger::config(self)
+    }
+
+    async fn pick_up(&mut self, filament: usize) -> Result<(), Error> {
+        self.move_to_filament(filament).await
+    }
+
+    async fn cut(&mut self, filament: usize) -> Result<(), Error> {
+        self.cut_filament(filament).await?;
+        self.set_lane(filament, LaneState::InHub);
+        Ok(())
+    }
+
+    async fn form_tip(&mut self, filament: usize) -> Result<(), Error> {
+        FilamentChanger::form_tip(self, filament).await
+    }
+
+    async fn unload(&mut self, filament: usize) -> Result<(), Error> {
+        self.unload_to_parked(filament).await
+    }
+
+    async fn wait_until(&mut self, start: Instant, duration: Duration) -> Result<(), Error> {
+        FilamentChanger::wait_until(self, start, duration).await
+    }
+}
@@ -0,0 +1,68 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use embassy_futures::select::{select, Either};
+use embassy_time::{Duration, Ticker};
+use esp_hal::gpio::Output;
+
+use crate::events::{self, Event, EventSubscriber, Sensor};
+
+const PRESS_BLINK_INTERVAL: Duration = Duration::from_millis(500);
+
+// Blinks the LED while the endswitch is held, so the length of the press can
+// be counted when selecting a filament by hand.
+pub async fn run_led(mut led: Output<'_>) {
+    let mut events = events::subscribe();
+    loop {
+        if let Event::SensorChanged {
+            sensor: Sensor::Endswitch,
+            triggered: true,
+        } = events.next_message_pure().await
+        {
+            led.set_low();
+            blink_until_released(&mut led, &mut events).await;
+        }
+    }
+}
+
+async fn blink_until_released(led: &mut Output<'_>, events: &mut EventSubscriber) {
+    let mut ticker = Ticker::every(PRESS_BLINK_INTERVAL);
+    loop {
+        match select(ticker.next(), events.next_message_pure()).await {
+            Either::First(()) => led.toggle(),
+            Either::Second(Event::SensorChanged {
+                sensor: Sensor::Endswitch,
+                triggered: false,
+            }) => return,
+            Either::Second(_) => {}
+        }
+    }
+}
+
+// Holds the printer fault signal while the MMU is in an error state, until a
+// successful homing clears it. Refused commands leave it alone.
+pub async fn run_fault_signal(mut fault: Output<'_>) {
+    let mut events = events::subscribe();
+    loop {
+        match events.next_message_pure().await {
+            Event::Error(error) if error.is_fault() => fault.set_high(),
+            Event::HomingCompleted => fault.set_low(),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,76 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::gpio::Input;
+
+use crate::{
+    command::{self, Command},
+    config::Config,
+    error::Error,
+    events::{self, Event, Sensor},
+};
+
+// The printer presses the endswitch for a time that encodes the command:
+// see `Config::abort_press_ms`, `Config::select_press_ms` and
+// `Config::homing_press_ms`.
+pub async fn run_endswitch(endswitch: Input<'_>, config: Config) {
+    // When the last abort tap was released, waiting for the second one.
+    let mut abort_tap: Option<Instant> = None;
+    loop {
+        if endswitch.is_high() {
+            log::debug!("Endswitch triggered");
+            events::publish(Event::SensorChanged {
+                sensor: Sensor::Endswitch,
+                triggered: true,
+            });
+            let start = Instant::now();
+            while endswitch.is_high() {
+                Timer::after(Duration::from_millis(10)).await;
+            }
+            let duration = start.elapsed();
+            events::publish(Event::SensorChanged {
+                sensor: Sensor::Endswitch,
+                triggered: false,
+            });
+
+            if config.is_abort_press(duration.as_millis()) {
+                let window = Duration::from_millis(config.abort_tap_window_ms);
+                if abort_tap.take().is_some_and(|tap| tap.elapsed() <= window) {
+                    log::info!("Abort command detected");
+                    command::abort();
+                } else {
+                    log::debug!("Abort tap, tap again to abort");
+                    abort_tap = Some(Instant::now());
+                }
+            } else if duration.as_millis() >= config.homing_press_ms {
+                log::info!("Homing command detected");
+                command::send(Command::Home).await;
+            } else if let Some(filament) = config.filament_for_press(duration.as_millis()) {
+                command::send(Command::Select(filament)).await;
+            } else {
+                log::warn!("Unexpected duration: {} ms", duration.as_millis());
+                events::publish(Event::Error(Error::UnexpectedPress {
+                    duration_ms: duration.as_millis(),
+                }));
+            }
+        }
+
+        Timer::after(Duration::from_millis(25)).await;
+    }
+}
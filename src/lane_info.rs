@@ -0,0 +1,131 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// What is in each lane, as entered over the console. Only the material
+// changes how the MMU moves the filament, the rest is kept and reported so a
+// host can check the right filament is in the right lane.
+
+use core::fmt;
+
+use crate::{material::Material, units::Millimeters};
+
+// Hottest nozzle temperature accepted, in °C.
+const MAX_NOZZLE_TEMP: u16 = 500;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+impl Rgb {
+    // From six hex digits, with or without a leading '#'.
+    pub fn from_hex(hex: &str) -> Option<Rgb> {
+        let hex = hex.strip_prefix('#').unwrap_or(hex);
+        if hex.len() != 6 {
+            return None;
+        }
+        let value = u32::from_str_radix(hex, 16).ok()?;
+        Some(Rgb::from_u32(value))
+    }
+
+    pub fn to_u32(self) -> u32 {
+        u32::from_be_bytes([0, self.0, self.1, self.2])
+    }
+
+    pub fn from_u32(value: u32) -> Rgb {
+        let [_, red, green, blue] = value.to_be_bytes();
+        Rgb(red, green, blue)
+    }
+}
+
+impl fmt::Display for Rgb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "#{:02x}{:02x}{:02x}", self.0, self.1, self.2)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LaneInfo {
+    pub material: Material,
+    pub colour: Option<Rgb>,
+    // Lowest and highest nozzle temperature, in °C.
+    pub nozzle_temp: Option<(u16, u16)>,
+    pub spool_id: Option<u32>,
+    pub remaining: Option<Millimeters>,
+}
+
+impl LaneInfo {
+    pub const DEFAULT: LaneInfo = LaneInfo {
+        material: Material::Pla,
+        colour: None,
+        nozzle_temp: None,
+        spool_id: None,
+        remaining: None,
+    };
+
+    // Whether every field can be kept as it is, an unset one always can.
+    pub fn is_valid(&self) -> bool {
+        let temp_valid = self
+            .nozzle_temp
+            .is_none_or(|(min, max)| min <= max && max <= MAX_NOZZLE_TEMP);
+        // u32::MAX reads back as erased flash
+        let spool_valid = self.spool_id != Some(u32::MAX);
+        let remaining_valid = self
+            .remaining
+            .is_none_or(|remaining| remaining.0.is_finite() && remaining.0 >= 0.0);
+        temp_valid && spool_valid && remaining_valid
+    }
+}
+
+// The material and whatever else is known, e.g. "pla, #ff8800, 190-220C".
+impl fmt::Display for LaneInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.material.name())?;
+        if let Some(colour) = self.colour {
+            write!(f, ", {}", colour)?;
+        }
+        if let Some((min, max)) = self.nozzle_temp {
+            write!(f, ", {}-{}C", min, max)?;
+        }
+        if let Some(spool_id) = self.spool_id {
+            write!(f, ", spool {}", spool_id)?;
+        }
+        if let Some(remaining) = self.remaining {
+            write!(f, ", {}mm left", remaining.0)?;
+        }
+        Ok(())
+    }
+}
+
+// One change to the information of a lane, None clearing the field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LaneInfoUpdate {
+    Colour(Option<Rgb>),
+    NozzleTemp(Option<(u16, u16)>),
+    SpoolId(Option<u32>),
+    Remaining(Option<Millimeters>),
+}
+
+impl LaneInfoUpdate {
+    pub fn apply(self, info: &mut LaneInfo) {
+        match self {
+            LaneInfoUpdate::Colour(colour) => info.colour = colour,
+            LaneInfoUpdate::NozzleTemp(nozzle_temp) => info.nozzle_temp = nozzle_temp,
+            LaneInfoUpdate::SpoolId(spool_id) => info.spool_id = spool_id,
+            LaneInfoUpdate::Remaining(remaining) => info.remaining = remaining,
+        }
+    }
+}
@@ -0,0 +1,123 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Where the filament of each lane is. Every cut, unload and load step
+// updates it, and the selector refuses to move while a filament it would
+// drag sideways is still in the path.
+
+use crate::{
+    config::{Config, FILAMENT_COUNT},
+    error::Error,
+    units::Steps,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LaneState {
+    // Not tracked yet, e.g. on the first boot without a journal.
+    Unknown,
+    // Retracted behind the selector, clear of the hub.
+    Parked,
+    // Through the selector and into the hub, but not at the nozzle.
+    InHub,
+    // Loaded all the way to the printer.
+    AtNozzle,
+}
+
+impl LaneState {
+    pub fn name(self) -> &'static str {
+        match self {
+            LaneState::Unknown => "unknown",
+            LaneState::Parked => "parked",
+            LaneState::InHub => "in hub",
+            LaneState::AtNozzle => "at nozzle",
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            LaneState::Unknown => 0,
+            LaneState::Parked => 1,
+            LaneState::InHub => 2,
+            LaneState::AtNozzle => 3,
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(LaneState::Unknown),
+            1 => Some(LaneState::Parked),
+            2 => Some(LaneState::InHub),
+            3 => Some(LaneState::AtNozzle),
+            _ => None,
+        }
+    }
+}
+
+pub type Lanes = [LaneState; FILAMENT_COUNT];
+
+pub const UNKNOWN_LANES: Lanes = [LaneState::Unknown; FILAMENT_COUNT];
+
+// With the position unknown after a reset, the selector can only be at the
+// loaded lane `selected` or its park position, as those are the only places
+// it goes while the lane is loaded. It is homed with that filament engaged
+// and brought back to it, as long as every other lane is parked.
+pub fn check_recovery_move(lanes: &Lanes, selected: usize) -> Result<(), Error> {
+    match lanes
+        .iter()
+        .enumerate()
+        .find(|&(filament, &state)| filament != selected && state != LaneState::Parked)
+    {
+        Some((filament, _)) => Err(Error::LaneEngaged { filament }),
+        None => Ok(()),
+    }
+}
+
+// A selector move from `from` to `to` is only safe when every lane is
+// parked, except for the selected lane moving between its own position and
+// its park position, which is how it is parked and picked up again. From an
+// unknown position every lane has to be parked. Filament at the hub sensor
+// while every lane is parked means an unload fell short, which blocks the
+// move as well.
+pub fn check_selector_move(
+    config: &Config,
+    lanes: &Lanes,
+    selected: Option<usize>,
+    hub_occupied: bool,
+    from: Option<Steps>,
+    to: Steps,
+) -> Result<(), Error> {
+    let mut engaged = false;
+    for (filament, &state) in lanes.iter().enumerate() {
+        if state == LaneState::Parked {
+            continue;
+        }
+        let own = |position: Steps| {
+            position == config.lane_position(filament) || config.may_park_at(filament, position)
+        };
+        let own_move = selected == Some(filament) && from.is_some_and(own) && own(to);
+        if !own_move {
+            return Err(Error::LaneEngaged { filament });
+        }
+        engaged = true;
+    }
+
+    if hub_occupied && !engaged {
+        return Err(Error::HubOccupied);
+    }
+    Ok(())
+}
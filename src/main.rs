@@ -20,6 +20,8 @@ along with this program.  If not, see <http://www.gnu.org/licenses/>.
 #![no_main]
 #![feature(type_alias_impl_trait)]
 
+use config::Config;
+use cutter::{AnyCutter, CutterKind, PrinterCutter, ServoCutter, SolenoidCutter};
 use embassy_executor::Spawner;
 use embassy_time::{Duration, Timer};
 use esp_backtrace as _;
@@ -32,11 +34,39 @@ use esp_hal::{
     },
     peripherals::MCPWM0,
     prelude::*,
-    timer::timg::TimerGroup,
+    timer::timg::{MwdtStage, TimerGroup},
+    uart::UartRx,
+    Async,
 };
+use esp_storage::FlashStorage;
 use filament_changer::FilamentChanger;
-
+use servo::Servo;
+use settings::SettingsStore;
+use storage::Journal;
+
+mod command;
+mod config;
+mod console;
+mod cutter;
+mod error;
+mod events;
 mod filament_changer;
+mod indicator;
+mod input;
+mod lane_info;
+mod lanes;
+mod material;
+mod motion;
+mod safe_state;
+mod servo;
+mod settings;
+#[cfg(feature = "slicer-profile")]
+mod slicer;
+mod status;
+mod storage;
+mod timing;
+mod toolchange;
+mod units;
 
 extern crate alloc;
 
@@ -45,14 +75,30 @@ async fn filament_changer_task(mut filament_changer: FilamentChanger<'static>) {
     filament_changer.run().await;
 }
 
-/*
-Servo Motor Limits:
-    300 is min
-    2500 is max
-    0deg is 500
-    90deg is 1500
-    180deg is 2500
-*/
+#[embassy_executor::task]
+async fn journal_task(journal: Journal) {
+    storage::run_journal(journal).await;
+}
+
+#[embassy_executor::task]
+async fn endswitch_task(endswitch: Input<'static>, config: Config) {
+    input::run_endswitch(endswitch, config).await;
+}
+
+#[embassy_executor::task]
+async fn console_task(rx: UartRx<'static, Async>) {
+    console::run_console(rx).await;
+}
+
+#[embassy_executor::task]
+async fn led_task(led: Output<'static>) {
+    indicator::run_led(led).await;
+}
+
+#[embassy_executor::task]
+async fn fault_signal_task(fault: Output<'static>) {
+    indicator::run_fault_signal(fault).await;
+}
 
 #[esp_hal_embassy::main]
 async fn main(spawner: Spawner) -> ! {
@@ -63,10 +109,29 @@ async fn main(spawner: Spawner) -> ! {
 
     esp_alloc::heap_allocator!(72 * 1024);
 
+    // calibration results override the defaults
+    let mut config = Config::DEFAULT;
+    let mut settings = SettingsStore::new(FlashStorage::new());
+    match settings.load() {
+        Some(saved) => saved.apply(&mut config),
+        None => log::info!("No saved settings found"),
+    }
+
+    #[cfg(feature = "slicer-profile")]
+    slicer::SlicerProfile::ANKERMAKE_M5
+        .write_all(&mut esp_println::Printer, &config)
+        .unwrap();
+
     // initialize embasy
     let timg1 = TimerGroup::new(peripherals.TIMG1);
     esp_hal_embassy::init(timg1.timer0);
 
+    // watchdog fed by the filament changer, resets the MCU if it hangs
+    let timg0 = TimerGroup::new(peripherals.TIMG0);
+    let mut watchdog = timg0.wdt;
+    watchdog.set_timeout(MwdtStage::Stage0, 5u64.secs());
+    watchdog.enable();
+
     // initialize filament changer
 
     // Pin configuration
@@ -81,8 +146,30 @@ async fn main(spawner: Spawner) -> ! {
 
     let endswitch = Input::new(peripherals.GPIO19, Pull::Down);
 
+    // Optional, high while filament is in the hub
+    let hub_sensor = config
+        .hub_sensor
+        .then(|| Input::new(peripherals.GPIO32, Pull::Down));
+    // Optional, high while filament is at the printer extruder
+    let toolhead_sensor = config
+        .toolhead_sensor
+        .then(|| Input::new(peripherals.GPIO33, Pull::Down));
+    // Optional, high while the selector is at its home position
+    let selector_endstop = config
+        .selector_endstop
+        .then(|| Input::new(peripherals.GPIO26, Pull::Down));
+
     let led = Output::new(peripherals.GPIO2, Level::Low);
 
+    // Console commands come in on the USB serial port, the log goes out on it
+    let console_rx = UartRx::new(peripherals.UART0, peripherals.GPIO3)
+        .unwrap()
+        .into_async();
+
+    // Asserted towards the printer while the MMU is in an error state.
+    // Also driven by the panic handler in safe_state.rs.
+    let fault = Output::new(peripherals.GPIO22, Level::Low);
+
     // MCPWM setup ( for Servo )
     let clock_cfg = PeripheralClockConfig::with_frequency(32.MHz()).unwrap();
 
@@ -91,7 +178,7 @@ async fn main(spawner: Spawner) -> ! {
     // connect operator0 to timer0
     mcpwm.operator0.set_timer(&mcpwm.timer0);
     // connect operator0 to pin
-    let mut pwm_pin: PwmPin<'_, MCPWM0, 0, true> = mcpwm
+    let pwm_pin: PwmPin<'_, MCPWM0, 0, true> = mcpwm
         .operator0
         .with_pin_a(servo_pin, PwmPinConfig::UP_ACTIVE_HIGH);
 
@@ -102,7 +189,21 @@ async fn main(spawner: Spawner) -> ! {
         .unwrap();
     mcpwm.timer0.start(timer_clock_cfg);
 
-    pwm_pin.set_timestamp(1000);
+    let cutter = match config.cutter {
+        CutterKind::Servo => {
+            let mut servo = Servo::new(pwm_pin, config.servo);
+            servo.set_angle(config.servo_resting_angle);
+            AnyCutter::Servo(ServoCutter::new(servo, &config))
+        }
+        CutterKind::Solenoid => AnyCutter::Solenoid(SolenoidCutter::new(Output::new(
+            peripherals.GPIO25,
+            Level::Low,
+        ))),
+        CutterKind::Printer => AnyCutter::Printer(PrinterCutter),
+    };
+
+    // read before the changer starts, which queues the next records
+    let mut journal = Journal::new(FlashStorage::new());
 
     let filament_changer = FilamentChanger::new(
         stepper_a_dir,
@@ -111,14 +212,24 @@ async fn main(spawner: Spawner) -> ! {
         stepper_b_dir,
         stepper_b_step,
         stepper_b_en,
-        endswitch,
-        led,
-        pwm_pin,
+        cutter,
+        hub_sensor,
+        toolhead_sensor,
+        selector_endstop,
+        config,
+        journal.load(),
+        settings,
+        watchdog,
     );
 
     spawner
         .spawn(filament_changer_task(filament_changer))
         .unwrap();
+    spawner.spawn(journal_task(journal)).unwrap();
+    spawner.spawn(endswitch_task(endswitch, config)).unwrap();
+    spawner.spawn(console_task(console_rx)).unwrap();
+    spawner.spawn(led_task(led)).unwrap();
+    spawner.spawn(fault_signal_task(fault)).unwrap();
 
     loop {
         Timer::after(Duration::from_millis(5_000)).await;
@@ -0,0 +1,56 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+pub const MATERIAL_COUNT: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Material {
+    Pla,
+    Petg,
+    Tpu,
+}
+
+impl Material {
+    pub const ALL: [Material; MATERIAL_COUNT] = [Material::Pla, Material::Petg, Material::Tpu];
+
+    pub fn index(self) -> usize {
+        match self {
+            Material::Pla => 0,
+            Material::Petg => 1,
+            Material::Tpu => 2,
+        }
+    }
+
+    pub fn from_index(index: usize) -> Option<Self> {
+        Self::ALL.get(index).copied()
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Material::Pla => "pla",
+            Material::Petg => "petg",
+            Material::Tpu => "tpu",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|material| material.name().eq_ignore_ascii_case(name))
+    }
+}
@@ -0,0 +1,61 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use core::{future::Future, pin::pin};
+
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::{peripherals::TIMG0, timer::timg::Wdt};
+
+use crate::{command, error::Error};
+
+pub type Watchdog = Wdt<TIMG0>;
+
+// Waits for `duration` unless an abort is requested first. Every wait
+// feeds the watchdog, so motion that stops making progress resets the MCU.
+pub async fn wait(watchdog: &mut Watchdog, duration: Duration) -> Result<(), Error> {
+    watchdog.feed();
+    match select(Timer::after(duration), command::aborted()).await {
+        Either::First(()) => Ok(()),
+        Either::Second(()) => Err(Error::Aborted),
+    }
+}
+
+// Waits until `done` completes or `timeout` runs out, and tells which came
+// first. The watchdog is fed every second, so the timeout can outlast it.
+pub async fn wait_for(
+    watchdog: &mut Watchdog,
+    done: impl Future<Output = ()>,
+    timeout: Duration,
+) -> Result<bool, Error> {
+    let deadline = Instant::now() + timeout;
+    let mut done = pin!(done);
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        watchdog.feed();
+        let slice = Timer::after((deadline - now).min(Duration::from_secs(1)));
+        match select3(&mut done, slice, command::aborted()).await {
+            Either3::First(()) => return Ok(true),
+            Either3::Second(()) => {}
+            Either3::Third(()) => return Err(Error::Aborted),
+        }
+    }
+}
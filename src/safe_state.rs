@@ -0,0 +1,80 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Safe state on panic or CPU exception: esp-backtrace calls
+// `custom_pre_backtrace` before printing the backtrace and `custom_halt`
+// instead of halting. Whatever owned the pins is gone by then, so the
+// peripherals are stolen and driven directly. The pins must match main.rs.
+
+use esp_hal::{
+    delay::Delay,
+    gpio::{Level, Output},
+    mcpwm::{operator::PwmPinConfig, timer::PwmWorkingMode, McPwm, PeripheralClockConfig},
+    peripherals::Peripherals,
+    prelude::*,
+};
+
+use crate::{config::Config, cutter::CutterKind};
+
+const SERVO_PARK_TIME_MS: u32 = 500;
+
+#[no_mangle]
+unsafe fn custom_pre_backtrace() {
+    let peripherals = Peripherals::steal();
+
+    // de-energize both steppers (enable is active low)
+    core::mem::forget(Output::new(peripherals.GPIO16, Level::High));
+    core::mem::forget(Output::new(peripherals.GPIO18, Level::High));
+
+    // tell the printer something went wrong
+    core::mem::forget(Output::new(peripherals.GPIO22, Level::High));
+
+    let config = Config::DEFAULT;
+
+    // release the solenoid so the blade doesn't stay in the filament path
+    if config.cutter == CutterKind::Solenoid {
+        core::mem::forget(Output::new(peripherals.GPIO25, Level::Low));
+    }
+
+    // park the servo, for the same reason
+    if config.cutter != CutterKind::Servo {
+        return;
+    }
+    if let Ok(clock_cfg) = PeripheralClockConfig::with_frequency(32.MHz()) {
+        let mut mcpwm = McPwm::new(peripherals.MCPWM0, clock_cfg);
+        mcpwm.operator0.set_timer(&mcpwm.timer0);
+        let mut pwm_pin = mcpwm
+            .operator0
+            .with_pin_a(peripherals.GPIO23, PwmPinConfig::UP_ACTIVE_HIGH);
+        if let Ok(timer_clock_cfg) =
+            clock_cfg.timer_clock_with_frequency(20000, PwmWorkingMode::Increase, 50.Hz())
+        {
+            mcpwm.timer0.start(timer_clock_cfg);
+            pwm_pin.set_timestamp(config.servo.pulse(config.servo_resting_angle).0);
+            Delay::new().delay_millis(SERVO_PARK_TIME_MS);
+        }
+    }
+}
+
+#[no_mangle]
+unsafe fn custom_halt() -> ! {
+    esp_hal::reset::software_reset();
+    loop {
+        core::hint::spin_loop();
+    }
+}
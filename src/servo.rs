@@ -0,0 +1,114 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Hobby servo on MCPWM0, positioned in degrees. The PWM timer runs at 50Hz
+// with a period of 20000 ticks, so a timestamp is the pulse width in µs.
+
+use embassy_time::Duration;
+use esp_hal::{mcpwm::operator::PwmPin, peripherals::MCPWM0};
+
+use crate::units::ServoPulse;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ServoConfig {
+    // Pulse widths of the 0° and 180° ends of the servo's travel.
+    pub pulse_at_0_deg: ServoPulse,
+    pub pulse_at_180_deg: ServoPulse,
+    // Calibrated limits, every pulse is clamped to them.
+    pub min_pulse: ServoPulse,
+    pub max_pulse: ServoPulse,
+    // Moves are split into steps of at most `ramp_step`, one every
+    // `ramp_interval`, to soften the blade hitting the filament. 0 jumps
+    // straight to the target.
+    pub ramp_step: ServoPulse,
+    pub ramp_interval: Duration,
+    // Stop the pulses `detach_delay` after parking, so the servo doesn't
+    // jitter and heat up while idle.
+    pub detach: bool,
+    pub detach_delay: Duration,
+}
+
+impl ServoConfig {
+    pub fn pulse(&self, angle: f32) -> ServoPulse {
+        let span = (self.pulse_at_180_deg.0 as f32) - (self.pulse_at_0_deg.0 as f32);
+        let pulse = self.pulse_at_0_deg.0 as f32 + span * angle / 180.0;
+        ServoPulse(pulse as u16).clamp(self.min_pulse, self.max_pulse)
+    }
+
+    // How many ramp steps a move from `from` to `to` takes.
+    pub fn ramp_steps(&self, from: f32, to: f32) -> u32 {
+        if self.ramp_step.0 == 0 {
+            return 0;
+        }
+        let distance = self.pulse(from).0.abs_diff(self.pulse(to).0);
+        distance.div_ceil(self.ramp_step.0) as u32
+    }
+}
+
+pub struct Servo<'a> {
+    pwm_pin: PwmPin<'a, MCPWM0, 0, true>,
+    config: ServoConfig,
+    // None while detached.
+    pulse: Option<ServoPulse>,
+}
+
+impl<'a> Servo<'a> {
+    pub fn new(pwm_pin: PwmPin<'a, MCPWM0, 0, true>, config: ServoConfig) -> Self {
+        Self {
+            pwm_pin,
+            config,
+            pulse: None,
+        }
+    }
+
+    // Jumps to `angle` without ramping.
+    pub fn set_angle(&mut self, angle: f32) {
+        self.set_pulse(self.config.pulse(angle));
+    }
+
+    // Moves one ramp step towards `angle`. Returns false once it is there,
+    // so a caller waits `ramp_interval` after every true. A detached servo
+    // has no known position to ramp from and jumps.
+    pub fn step_towards(&mut self, angle: f32) -> bool {
+        let target = self.config.pulse(angle);
+        let next = match self.pulse {
+            Some(pulse) if pulse == target => return false,
+            Some(ServoPulse(pulse)) if self.config.ramp_step.0 > 0 => {
+                let ramp_step = self.config.ramp_step.0;
+                if pulse < target.0 {
+                    ServoPulse(pulse.saturating_add(ramp_step)).min(target)
+                } else {
+                    ServoPulse(pulse.saturating_sub(ramp_step)).max(target)
+                }
+            }
+            _ => target,
+        };
+        self.set_pulse(next);
+        next != target
+    }
+
+    pub fn detach(&mut self) {
+        self.pwm_pin.set_timestamp(0);
+        self.pulse = None;
+    }
+
+    fn set_pulse(&mut self, pulse: ServoPulse) {
+        self.pwm_pin.set_timestamp(pulse.0);
+        self.pulse = Some(pulse);
+    }
+}
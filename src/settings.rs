@@ -0,0 +1,386 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Calibration results and lane information persisted in flash, applied over
+// `Config::DEFAULT` on boot.
+//
+// The record is written alternately to two sectors, so a power loss while
+// saving leaves the previous one intact. Every field sits at a fixed offset
+// and erased flash (0xff) reads as "not calibrated", so fields added later
+// leave older records valid.
+
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use esp_storage::FlashStorage;
+
+use crate::{
+    config::{Backlash, BowdenLengths, Config, SelectorPark, FILAMENT_COUNT},
+    lane_info::{LaneInfo, Rgb},
+    material::Material,
+    storage::{crc32, is_newer},
+    toolchange::ToolchangeKind,
+    units::{Millimeters, Steps, StepsPerMm},
+};
+
+// The two sectors after the journal, at the end of the `nvs` partition.
+const SETTINGS_OFFSET: u32 = 0xd000;
+const SECTOR_SIZE: u32 = FlashStorage::ERASE_SIZE as u32;
+const SECTOR_COUNT: u32 = 2;
+
+// magic: u16, padding, sequence: u32, fields, crc32: u32 in the last four
+// bytes.
+const RECORD_SIZE: usize = 256;
+const RECORD_MAGIC: u16 = 0x5301;
+const SEQUENCE_OFFSET: usize = 4;
+const CRC_OFFSET: usize = RECORD_SIZE - 4;
+
+// Per lane: selector to hub and hub to extruder, in mm.
+const BOWDEN_OFFSET: usize = 8;
+// Per lane: selector position in steps.
+const POSITIONS_OFFSET: usize = BOWDEN_OFFSET + FILAMENT_COUNT * 8;
+const STEPS_PER_MM_OFFSET: usize = POSITIONS_OFFSET + FILAMENT_COUNT * 4;
+// mode: u32, steps: u32
+const BACKLASH_OFFSET: usize = STEPS_PER_MM_OFFSET + 4;
+// Per lane: material index, colour 0x00rrggbb, nozzle temperatures as
+// min << 16 | max, spool ID, remaining mm.
+const LANE_INFO_OFFSET: usize = BACKLASH_OFFSET + 8;
+const LANE_INFO_SIZE: usize = 20;
+// kind: u32
+const TOOLCHANGE_OFFSET: usize = LANE_INFO_OFFSET + FILAMENT_COUNT * LANE_INFO_SIZE;
+// Per lane: mode: u32, lane or position: u32
+const SELECTOR_PARK_OFFSET: usize = TOOLCHANGE_OFFSET + 4;
+const _: () = assert!(SELECTOR_PARK_OFFSET + FILAMENT_COUNT * 8 <= CRC_OFFSET);
+
+const BACKLASH_NONE: u32 = 0;
+const BACKLASH_COMPENSATE: u32 = 1;
+const BACKLASH_FINAL_APPROACH: u32 = 2;
+
+const TOOLCHANGE_CUT: u32 = 0;
+const TOOLCHANGE_TIP_FORMING: u32 = 1;
+
+const PARK_STAY: u32 = 0;
+const PARK_LANE: u32 = 1;
+const PARK_SLOT: u32 = 2;
+const PARK_POSITION: u32 = 3;
+
+// Only what was calibrated or set at runtime, so later changes to the
+// defaults in `config.rs` still apply to everything else.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Settings {
+    pub bowden: [Option<BowdenLengths>; FILAMENT_COUNT],
+    pub filament_positions: [Option<Steps>; FILAMENT_COUNT],
+    pub extruder_steps_per_mm: Option<f32>,
+    pub selector_backlash: Option<Backlash>,
+    pub lane_info: [Option<LaneInfo>; FILAMENT_COUNT],
+    pub toolchange: Option<ToolchangeKind>,
+    pub selector_park: [Option<SelectorPark>; FILAMENT_COUNT],
+}
+
+impl Settings {
+    pub fn apply(&self, config: &mut Config) {
+        for (((lane, bowden), position), park) in config
+            .lane_geometry
+            .iter_mut()
+            .zip(self.bowden)
+            .zip(self.filament_positions)
+            .zip(self.selector_park)
+        {
+            if let Some(bowden) = bowden {
+                lane.bowden = Some(bowden);
+            }
+            if let Some(position) = position {
+                lane.selector_position = position;
+            }
+            if let Some(park) = park {
+                lane.park = park;
+            }
+        }
+        if let Some(steps_per_mm) = self.extruder_steps_per_mm {
+            config.extruder_steps_per_mm = StepsPerMm(steps_per_mm);
+        }
+        if let Some(backlash) = self.selector_backlash {
+            config.selector_backlash = backlash;
+        }
+        for (info, saved) in config.lane_info.iter_mut().zip(self.lane_info) {
+            if let Some(saved) = saved {
+                *info = saved;
+            }
+        }
+        if let Some(toolchange) = self.toolchange {
+            config.toolchange = toolchange;
+        }
+    }
+
+    fn encode(&self, sequence: u32) -> [u8; RECORD_SIZE] {
+        let mut record = [0xffu8; RECORD_SIZE];
+        record[0..2].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        record[SEQUENCE_OFFSET..SEQUENCE_OFFSET + 4].copy_from_slice(&sequence.to_le_bytes());
+        for (lane, lengths) in self.bowden.iter().enumerate() {
+            let offset = BOWDEN_OFFSET + lane * 8;
+            put_f32(
+                &mut record,
+                offset,
+                lengths.map(|lengths| lengths.selector_to_hub_mm.0),
+            );
+            put_f32(
+                &mut record,
+                offset + 4,
+                lengths.map(|lengths| lengths.hub_to_extruder_mm.0),
+            );
+        }
+        for (lane, position) in self.filament_positions.iter().enumerate() {
+            put_u32(
+                &mut record,
+                POSITIONS_OFFSET + lane * 4,
+                position.map(|steps| steps.0),
+            );
+        }
+        put_f32(&mut record, STEPS_PER_MM_OFFSET, self.extruder_steps_per_mm);
+        if let Some(backlash) = self.selector_backlash {
+            let (mode, steps) = match backlash {
+                Backlash::None => (BACKLASH_NONE, Steps(0)),
+                Backlash::Compensate(steps) => (BACKLASH_COMPENSATE, steps),
+                Backlash::FinalApproach(steps) => (BACKLASH_FINAL_APPROACH, steps),
+            };
+            put_u32(&mut record, BACKLASH_OFFSET, Some(mode));
+            put_u32(&mut record, BACKLASH_OFFSET + 4, Some(steps.0));
+        }
+        for (lane, info) in self.lane_info.iter().enumerate() {
+            let Some(info) = info else {
+                continue;
+            };
+            let offset = LANE_INFO_OFFSET + lane * LANE_INFO_SIZE;
+            put_u32(&mut record, offset, Some(info.material.index() as u32));
+            put_u32(&mut record, offset + 4, info.colour.map(Rgb::to_u32));
+            put_u32(
+                &mut record,
+                offset + 8,
+                info.nozzle_temp
+                    .map(|(min, max)| (min as u32) << 16 | max as u32),
+            );
+            put_u32(&mut record, offset + 12, info.spool_id);
+            put_f32(&mut record, offset + 16, info.remaining.map(|mm| mm.0));
+        }
+        if let Some(toolchange) = self.toolchange {
+            let kind = match toolchange {
+                ToolchangeKind::Cut => TOOLCHANGE_CUT,
+                ToolchangeKind::TipForming => TOOLCHANGE_TIP_FORMING,
+            };
+            put_u32(&mut record, TOOLCHANGE_OFFSET, Some(kind));
+        }
+        for (lane, park) in self.selector_park.iter().enumerate() {
+            let Some(park) = park else {
+                continue;
+            };
+            let (mode, value) = match *park {
+                SelectorPark::Stay => (PARK_STAY, 0),
+                SelectorPark::Lane(other) => (PARK_LANE, other as u32),
+                SelectorPark::Slot => (PARK_SLOT, 0),
+                SelectorPark::Position(position) => (PARK_POSITION, position.0),
+            };
+            let offset = SELECTOR_PARK_OFFSET + lane * 8;
+            put_u32(&mut record, offset, Some(mode));
+            put_u32(&mut record, offset + 4, Some(value));
+        }
+        let crc = crc32(&record[..CRC_OFFSET]);
+        record[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+        record
+    }
+
+    fn decode(record: &[u8; RECORD_SIZE]) -> Option<(u32, Settings)> {
+        let magic = u16::from_le_bytes([record[0], record[1]]);
+        if magic != RECORD_MAGIC
+            || get_u32(record, CRC_OFFSET) != Some(crc32(&record[..CRC_OFFSET]))
+        {
+            return None;
+        }
+
+        let mut bowden = [None; FILAMENT_COUNT];
+        for (lane, lengths) in bowden.iter_mut().enumerate() {
+            let offset = BOWDEN_OFFSET + lane * 8;
+            *lengths = match (get_f32(record, offset), get_f32(record, offset + 4)) {
+                (Some(selector_to_hub_mm), Some(hub_to_extruder_mm)) => Some(BowdenLengths {
+                    selector_to_hub_mm: Millimeters(selector_to_hub_mm),
+                    hub_to_extruder_mm: Millimeters(hub_to_extruder_mm),
+                }),
+                _ => None,
+            };
+        }
+        let mut filament_positions = [None; FILAMENT_COUNT];
+        for (lane, position) in filament_positions.iter_mut().enumerate() {
+            *position = get_u32(record, POSITIONS_OFFSET + lane * 4).map(Steps);
+        }
+        let backlash_steps = get_u32(record, BACKLASH_OFFSET + 4).map(Steps);
+        let selector_backlash = match (get_u32(record, BACKLASH_OFFSET), backlash_steps) {
+            (Some(BACKLASH_NONE), _) => Some(Backlash::None),
+            (Some(BACKLASH_COMPENSATE), Some(steps)) => Some(Backlash::Compensate(steps)),
+            (Some(BACKLASH_FINAL_APPROACH), Some(steps)) => Some(Backlash::FinalApproach(steps)),
+            _ => None,
+        };
+        let mut lane_info = [None; FILAMENT_COUNT];
+        for (lane, info) in lane_info.iter_mut().enumerate() {
+            let offset = LANE_INFO_OFFSET + lane * LANE_INFO_SIZE;
+            *info = get_u32(record, offset)
+                .and_then(|index| Material::from_index(index as usize))
+                .map(|material| LaneInfo {
+                    material,
+                    colour: get_u32(record, offset + 4).map(Rgb::from_u32),
+                    nozzle_temp: get_u32(record, offset + 8)
+                        .map(|temps| ((temps >> 16) as u16, temps as u16)),
+                    spool_id: get_u32(record, offset + 12),
+                    remaining: get_f32(record, offset + 16).map(Millimeters),
+                });
+        }
+        let toolchange = match get_u32(record, TOOLCHANGE_OFFSET) {
+            Some(TOOLCHANGE_CUT) => Some(ToolchangeKind::Cut),
+            Some(TOOLCHANGE_TIP_FORMING) => Some(ToolchangeKind::TipForming),
+            _ => None,
+        };
+        let mut selector_park = [None; FILAMENT_COUNT];
+        for (lane, park) in selector_park.iter_mut().enumerate() {
+            let offset = SELECTOR_PARK_OFFSET + lane * 8;
+            *park = match (get_u32(record, offset), get_u32(record, offset + 4)) {
+                (Some(PARK_STAY), _) => Some(SelectorPark::Stay),
+                (Some(PARK_LANE), Some(other)) if (other as usize) < FILAMENT_COUNT => {
+                    Some(SelectorPark::Lane(other as usize))
+                }
+                (Some(PARK_SLOT), _) => Some(SelectorPark::Slot),
+                (Some(PARK_POSITION), Some(position)) => {
+                    Some(SelectorPark::Position(Steps(position)))
+                }
+                _ => None,
+            };
+        }
+        let sequence = u32::from_le_bytes([
+            record[SEQUENCE_OFFSET],
+            record[SEQUENCE_OFFSET + 1],
+            record[SEQUENCE_OFFSET + 2],
+            record[SEQUENCE_OFFSET + 3],
+        ]);
+        Some((
+            sequence,
+            Settings {
+                bowden,
+                filament_positions,
+                extruder_steps_per_mm: get_f32(record, STEPS_PER_MM_OFFSET),
+                selector_backlash,
+                lane_info,
+                toolchange,
+                selector_park,
+            },
+        ))
+    }
+}
+
+pub struct SettingsStore {
+    flash: FlashStorage,
+    sequence: u32,
+    // Sector holding the newest record, the next save goes to the other.
+    current_sector: u32,
+    saved: Settings,
+}
+
+impl SettingsStore {
+    pub fn new(flash: FlashStorage) -> Self {
+        Self {
+            flash,
+            sequence: 0,
+            current_sector: SECTOR_COUNT - 1,
+            saved: Settings::default(),
+        }
+    }
+
+    pub fn load(&mut self) -> Option<Settings> {
+        let mut latest: Option<(u32, u32, Settings)> = None;
+        for sector in 0..SECTOR_COUNT {
+            let mut record = [0u8; RECORD_SIZE];
+            if let Err(error) = self.flash.read(sector_offset(sector), &mut record) {
+                log::warn!("Failed to read settings sector {}: {:?}", sector, error);
+                continue;
+            }
+            match (Settings::decode(&record), latest) {
+                (Some((sequence, _)), Some((latest_sequence, _, _)))
+                    if !is_newer(sequence, latest_sequence) => {}
+                (Some((sequence, settings)), _) => latest = Some((sequence, sector, settings)),
+                (None, _) => {}
+            }
+        }
+
+        let (sequence, sector, settings) = latest?;
+        self.sequence = sequence;
+        self.current_sector = sector;
+        self.saved = settings;
+        Some(settings)
+    }
+
+    // Sets fields of the saved settings and writes them, leaving the others
+    // as they were. Returns the settings now saved.
+    pub fn update(&mut self, change: impl FnOnce(&mut Settings)) -> Settings {
+        change(&mut self.saved);
+        let settings = self.saved;
+        self.save(&settings);
+        settings
+    }
+
+    // Failing to persist is logged, the settings still apply until a reset.
+    fn save(&mut self, settings: &Settings) {
+        let sector = (self.current_sector + 1) % SECTOR_COUNT;
+        let offset = sector_offset(sector);
+        let sequence = self.sequence.wrapping_add(1);
+        let result = self
+            .flash
+            .erase(offset, offset + SECTOR_SIZE)
+            .and_then(|()| self.flash.write(offset, &settings.encode(sequence)));
+        if let Err(error) = result {
+            log::warn!("Failed to save settings at {:#x}: {:?}", offset, error);
+            return;
+        }
+
+        self.sequence = sequence;
+        self.current_sector = sector;
+        log::info!("Settings saved");
+    }
+}
+
+fn sector_offset(sector: u32) -> u32 {
+    SETTINGS_OFFSET + sector * SECTOR_SIZE
+}
+
+fn put_f32(record: &mut [u8], offset: usize, value: Option<f32>) {
+    put_u32(record, offset, value.map(f32::to_bits));
+}
+
+fn put_u32(record: &mut [u8], offset: usize, value: Option<u32>) {
+    if let Some(value) = value {
+        record[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn get_f32(record: &[u8], offset: usize) -> Option<f32> {
+    get_u32(record, offset).map(f32::from_bits)
+}
+
+// None for erased flash.
+fn get_u32(record: &[u8], offset: usize) -> Option<u32> {
+    let value = u32::from_le_bytes([
+        record[offset],
+        record[offset + 1],
+        record[offset + 2],
+        record[offset + 3],
+    ]);
+    (value != u32::MAX).then_some(value)
+}
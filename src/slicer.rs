@@ -0,0 +1,269 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Generates the PrusaSlicer/OrcaSlicer start, end and toolchange G-code
+// snippets from the firmware `Config`, so the dwell times the printer waits
+// for the MMU are always derived from the real step counts and speeds.
+
+use core::fmt::{self, Write};
+
+use embassy_time::Duration;
+
+use crate::{config::Config, cutter::CutterKind, timing, toolchange::ToolchangeKind};
+
+/// Printer-side parameters of the profile: where the endswitch trigger is
+/// and how the printer feeds filament around a change.
+pub struct SlicerProfile {
+    pub name: &'static str,
+    pub trigger_y: f32,
+    pub trigger_travel: f32,
+    pub travel_feedrate: u32,
+    pub press_feedrate: u32,
+    pub pre_cut_retract_mm: f32,
+    pub pre_cut_retract_feedrate: u32,
+    pub splitter_load_mm: f32,
+    pub splitter_load_feedrate: u32,
+    // How much longer than the worst case the printer waits.
+    pub wait_margin: Duration,
+    // How much longer than the homing threshold the endswitch is held.
+    pub press_margin_ms: u64,
+    // Cuts the filament at the toolhead, for `CutterKind::Printer`. The host
+    // also has to answer the MMU's `request: cut <lane>` with `cut done`.
+    pub printer_cut_gcode: &'static [&'static str],
+}
+
+impl SlicerProfile {
+    pub const ANKERMAKE_M5: SlicerProfile = SlicerProfile {
+        name: "AnkerMake M5",
+        trigger_y: 3.0,
+        trigger_travel: 3.0,
+        travel_feedrate: 6000,
+        press_feedrate: 2000,
+        pre_cut_retract_mm: 60.0,
+        pre_cut_retract_feedrate: 500,
+        splitter_load_mm: 53.0,
+        splitter_load_feedrate: 200,
+        wait_margin: Duration::from_millis(500),
+        press_margin_ms: 250,
+        // no cutter at the toolhead
+        printer_cut_gcode: &[],
+    };
+
+    pub fn write_all(&self, w: &mut impl Write, config: &Config) -> fmt::Result {
+        writeln!(w, "; generic-mmu slicer profile for {}", self.name)?;
+        writeln!(w, "; add to Machine start G-code, after G28")?;
+        self.write_start_gcode(w, config)?;
+        writeln!(w)?;
+        writeln!(
+            w,
+            "; add to Machine end G-code, before turning off the heaters"
+        )?;
+        self.write_end_gcode(w, config)?;
+        writeln!(w)?;
+        writeln!(w, "; use as Change filament G-code")?;
+        self.write_toolchange_gcode(w, config)
+    }
+
+    pub fn write_start_gcode(&self, w: &mut impl Write, config: &Config) -> fmt::Result {
+        writeln!(w, "; HOME MMU")?;
+        self.write_move_to_trigger(w)?;
+        self.write_homing_press(w, config)?;
+        writeln!(
+            w,
+            "G4 P{} ; wait homing to complete",
+            self.wait_ms(timing::worst_case_home(config).total())
+        )
+    }
+
+    pub fn write_end_gcode(&self, w: &mut impl Write, config: &Config) -> fmt::Result {
+        writeln!(w, "; HOME MMU")?;
+        self.write_move_to_trigger(w)?;
+        if config.toolchange == ToolchangeKind::Cut {
+            writeln!(w, "; retract filament before cut from homing")?;
+            self.write_pre_cut_retract(w)?;
+        }
+        self.write_homing_press(w, config)?;
+        let home = timing::worst_case_home(config);
+        let wait = match config.toolchange {
+            ToolchangeKind::Cut => {
+                if config.cutter == CutterKind::Printer {
+                    self.write_printer_cut(w)?;
+                }
+                home.total()
+            }
+            ToolchangeKind::TipForming => {
+                self.write_tip_forming(w, config)?;
+                home.total() - home.unload.move_to_current - home.unload.tip
+            }
+        };
+        writeln!(w, "G4 P{} ; wait homing to complete", self.wait_ms(wait))
+    }
+
+    pub fn write_toolchange_gcode(&self, w: &mut impl Write, config: &Config) -> fmt::Result {
+        writeln!(
+            w,
+            "; begin switch from extruder T{{previous_extruder}} to extruder T{{next_extruder}}"
+        )?;
+        writeln!(w, "G90                ; Set all axes to absolute")?;
+        writeln!(w, "M83                ; Set extruder to relative mode")?;
+        writeln!(w, "G92 E0             ; Reset extruder position to 0")?;
+        if config.toolchange == ToolchangeKind::Cut {
+            writeln!(w, "{{if previous_extruder>-1}}")?;
+            writeln!(w, "; retract filament before cut")?;
+            self.write_pre_cut_retract(w)?;
+            writeln!(w, "{{endif}}")?;
+        }
+        self.write_move_to_trigger(w)?;
+
+        writeln!(w, "; Activate Extruder Switch")?;
+        writeln!(w, "G0 Y-{} F{}", self.trigger_travel, self.press_feedrate)?;
+        writeln!(w, "; Dwell based on the next extruder")?;
+        for (filament, &(min, max)) in config.select_press_ms.iter().enumerate() {
+            writeln!(w, "{{if next_extruder=={}}}", filament)?;
+            writeln!(w, "G4 P{}", (min + max) / 2)?;
+            writeln!(w, "{{endif}}")?;
+        }
+        writeln!(w, "G0 Y{}", self.trigger_travel)?;
+
+        writeln!(w, "{{if previous_extruder>-1}}")?;
+        match config.toolchange {
+            ToolchangeKind::Cut => {
+                if config.cutter == CutterKind::Printer {
+                    self.write_printer_cut(w)?;
+                }
+                writeln!(
+                    w,
+                    "; wait for the MMU to cut and unload the previous filament"
+                )?;
+                writeln!(
+                    w,
+                    "G4 P{}",
+                    self.wait_ms(timing::worst_case_change(config).unload_phase())
+                )?;
+            }
+            ToolchangeKind::TipForming => {
+                self.write_tip_forming(w, config)?;
+                writeln!(w, "; wait for the MMU to unload the previous filament")?;
+                writeln!(
+                    w,
+                    "G4 P{}",
+                    self.wait_ms(timing::worst_case_change(config).unload)
+                )?;
+            }
+        }
+        writeln!(w, "{{endif}}")?;
+        writeln!(w, "; wait for the MMU to select and load the next filament")?;
+        writeln!(
+            w,
+            "G4 P{}",
+            self.wait_ms(timing::worst_case_change(config).load_phase())
+        )?;
+
+        writeln!(w, "; Load filament from new extruder to splitter")?;
+        writeln!(
+            w,
+            "G1 E{} F{}",
+            self.splitter_load_mm, self.splitter_load_feedrate
+        )?;
+        writeln!(w, "G1 E-0.5 F2100     ; small retraction")?;
+        writeln!(w, "G92 E0             ; Reset extruder position to 0")?;
+        writeln!(w, "G90                ; Set all axes to absolute mode")?;
+        writeln!(w, "M83                ; Set extruder to relative mode")?;
+
+        writeln!(w, "{{if previous_extruder == -1}}")?;
+        writeln!(w, "G92 E0.0 ; reset extruder")?;
+        writeln!(w, "G1 X{{first_layer_print_max[0]+10}} Y{{first_layer_print_min[1]}} Z0.8 F6000.0 ; position 10mm right of the lower right of the first layer")?;
+        writeln!(w, "G1 X{{first_layer_print_max[0]+10}} Y{{first_layer_print_min[1]+30}} E30 F360.0 ; extrude 30mm of filament in the y direction")?;
+        writeln!(w, "G92 E0.0 ; reset extruder")?;
+        writeln!(w, "G1 E-0.5 F2100 ; small retraction")?;
+        writeln!(w, "G1 Y{{first_layer_print_min[1]+40}} F6000.0 ; move an additional 10mm without extruding")?;
+        writeln!(w, "G92 E0.0 ; reset extruder")?;
+        writeln!(w, "{{endif}}")
+    }
+
+    fn write_printer_cut(&self, w: &mut impl Write) -> fmt::Result {
+        writeln!(
+            w,
+            "; cut at the toolhead, the host answers the MMU with `cut done`"
+        )?;
+        for line in self.printer_cut_gcode {
+            writeln!(w, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    fn write_move_to_trigger(&self, w: &mut impl Write) -> fmt::Result {
+        writeln!(w, "G90                ; Set all axes to absolute")?;
+        writeln!(w, "M83                ; Set extruder to relative mode")?;
+        writeln!(w, "G92 E0             ; Reset extruder position to 0")?;
+        writeln!(
+            w,
+            "G0 Y{} F{}        ; Move to the trigger position",
+            self.trigger_y, self.travel_feedrate
+        )?;
+        writeln!(w, "G91                ; Set all axes to relative mode")
+    }
+
+    fn write_homing_press(&self, w: &mut impl Write, config: &Config) -> fmt::Result {
+        writeln!(w, "; Activate Switch")?;
+        writeln!(w, "G0 Y-{} F{}", self.trigger_travel, self.press_feedrate)?;
+        writeln!(w, "G4 P{}", config.homing_press_ms + self.press_margin_ms)?;
+        writeln!(w, "G0 Y{}", self.trigger_travel)
+    }
+
+    // Mirrors the MMU's tip forming moves on the printer extruder. The MMU
+    // pads picking up the filament to a fixed time, so both start together.
+    fn write_tip_forming(&self, w: &mut impl Write, config: &Config) -> fmt::Result {
+        let sequence = &config.tip_forming;
+        writeln!(w, "; wait for the MMU to pick up the previous filament")?;
+        writeln!(
+            w,
+            "G4 P{}",
+            timing::worst_move_to_current(config).as_millis()
+        )?;
+        writeln!(w, "; form the tip together with the MMU")?;
+        for tip_move in sequence.ramming {
+            writeln!(w, "G1 E{} F{}", tip_move.mm.0, tip_move.mm_per_min.0)?;
+        }
+        for _ in 0..sequence.cooling_moves {
+            writeln!(
+                w,
+                "G1 E{} F{}",
+                sequence.cooling_mm.0, sequence.cooling_mm_per_min.0
+            )?;
+            writeln!(
+                w,
+                "G1 E-{} F{}",
+                sequence.cooling_mm.0, sequence.cooling_mm_per_min.0
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write_pre_cut_retract(&self, w: &mut impl Write) -> fmt::Result {
+        writeln!(
+            w,
+            "G1 E-{} F{}",
+            self.pre_cut_retract_mm, self.pre_cut_retract_feedrate
+        )
+    }
+
+    fn wait_ms(&self, duration: Duration) -> u64 {
+        (duration + self.wait_margin).as_millis()
+    }
+}
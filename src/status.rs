@@ -0,0 +1,52 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Latest state of the filament changer, readable from any task. Status
+// queries are answered from here instead of going through the command
+// queue, so they don't wait for the current command to finish.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+
+use crate::{config::FILAMENT_COUNT, lane_info::LaneInfo, lanes::UNKNOWN_LANES, storage::Snapshot};
+
+static STATUS: Mutex<CriticalSectionRawMutex, Cell<Snapshot>> = Mutex::new(Cell::new(Snapshot {
+    current_filament: None,
+    current_position: None,
+    lanes: UNKNOWN_LANES,
+}));
+
+pub fn set(snapshot: Snapshot) {
+    STATUS.lock(|status| status.set(snapshot));
+}
+
+pub fn get() -> Snapshot {
+    STATUS.lock(|status| status.get())
+}
+
+static LANE_INFO: Mutex<CriticalSectionRawMutex, Cell<[LaneInfo; FILAMENT_COUNT]>> =
+    Mutex::new(Cell::new([LaneInfo::DEFAULT; FILAMENT_COUNT]));
+
+pub fn set_lane_info(lane_info: [LaneInfo; FILAMENT_COUNT]) {
+    LANE_INFO.lock(|status| status.set(lane_info));
+}
+
+pub fn lane_info() -> [LaneInfo; FILAMENT_COUNT] {
+    LANE_INFO.lock(|status| status.get())
+}
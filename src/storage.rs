@@ -0,0 +1,245 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Crash-safe journal of the MMU state in flash.
+//
+// Every state transition appends a small checksummed record to a ring of
+// flash sectors, erasing a sector only when the ring wraps into it, so the
+// writes are spread over the whole region. After a reset the record with the
+// highest sequence number is the last known state; a record torn by a power
+// loss fails its checksum and is ignored.
+//
+// Flash writes stall the whole executor, so the motion task only hands the
+// latest snapshot to `run_journal` and the write happens once it yields, at
+// the latest on its next step. Snapshots that pile up in between are
+// replaced by the newest one.
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+use esp_storage::FlashStorage;
+
+use crate::{
+    config::FILAMENT_COUNT,
+    lanes::{LaneState, Lanes},
+    units::Steps,
+};
+
+// The `nvs` partition of the default partition table, which the firmware
+// doesn't otherwise use. Its last two sectors hold the settings.
+const JOURNAL_OFFSET: u32 = 0x9000;
+const JOURNAL_SIZE: u32 = 0x4000;
+const SECTOR_SIZE: u32 = FlashStorage::ERASE_SIZE as u32;
+
+// magic: u16, filament: u8, flags: u8, sequence: u32, position: u32,
+// one state byte per lane, padding, crc32: u32 in the last four bytes.
+const RECORD_SIZE: usize = 32;
+const RECORD_MAGIC: u16 = 0x4d02;
+const LANES_OFFSET: usize = 12;
+const CRC_OFFSET: usize = RECORD_SIZE - 4;
+const _: () = assert!(LANES_OFFSET + FILAMENT_COUNT <= CRC_OFFSET);
+const SLOT_COUNT: u32 = JOURNAL_SIZE / RECORD_SIZE as u32;
+
+const NO_FILAMENT: u8 = 0xff;
+const FLAG_POSITION_KNOWN: u8 = 0x01;
+
+static PENDING: Signal<CriticalSectionRawMutex, Snapshot> = Signal::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub current_filament: Option<usize>,
+    // None while the selector is moving, so a reset mid-move is detected.
+    pub current_position: Option<Steps>,
+    pub lanes: Lanes,
+}
+
+impl Snapshot {
+    fn encode(&self, sequence: u32) -> [u8; RECORD_SIZE] {
+        let mut record = [0u8; RECORD_SIZE];
+        record[0..2].copy_from_slice(&RECORD_MAGIC.to_le_bytes());
+        record[2] = self
+            .current_filament
+            .map_or(NO_FILAMENT, |filament| filament as u8);
+        record[3] = if self.current_position.is_some() {
+            FLAG_POSITION_KNOWN
+        } else {
+            0
+        };
+        record[4..8].copy_from_slice(&sequence.to_le_bytes());
+        record[8..12].copy_from_slice(&self.current_position.unwrap_or_default().0.to_le_bytes());
+        for (byte, state) in record[LANES_OFFSET..].iter_mut().zip(self.lanes) {
+            *byte = state.to_u8();
+        }
+        let crc = crc32(&record[..CRC_OFFSET]);
+        record[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+        record
+    }
+
+    fn decode(record: &[u8; RECORD_SIZE]) -> Option<(u32, Snapshot)> {
+        let magic = u16::from_le_bytes([record[0], record[1]]);
+        let crc = u32::from_le_bytes([
+            record[CRC_OFFSET],
+            record[CRC_OFFSET + 1],
+            record[CRC_OFFSET + 2],
+            record[CRC_OFFSET + 3],
+        ]);
+        if magic != RECORD_MAGIC || crc != crc32(&record[..CRC_OFFSET]) {
+            return None;
+        }
+
+        let sequence = u32::from_le_bytes([record[4], record[5], record[6], record[7]]);
+        let position = u32::from_le_bytes([record[8], record[9], record[10], record[11]]);
+        let mut lanes = [LaneState::Unknown; FILAMENT_COUNT];
+        for (state, &byte) in lanes.iter_mut().zip(&record[LANES_OFFSET..]) {
+            *state = LaneState::from_u8(byte)?;
+        }
+        let snapshot = Snapshot {
+            current_filament: (record[2] != NO_FILAMENT).then_some(record[2] as usize),
+            current_position: (record[3] & FLAG_POSITION_KNOWN != 0).then_some(Steps(position)),
+            lanes,
+        };
+        Some((sequence, snapshot))
+    }
+}
+
+pub struct Journal {
+    flash: FlashStorage,
+    next_slot: u32,
+    sequence: u32,
+    last: Option<Snapshot>,
+}
+
+impl Journal {
+    pub fn new(flash: FlashStorage) -> Self {
+        Self {
+            flash,
+            next_slot: 0,
+            sequence: 0,
+            last: None,
+        }
+    }
+
+    // Scans the journal for the most recent record. Has to be called before
+    // `run_journal`, so new records continue after the existing ones.
+    pub fn load(&mut self) -> Option<Snapshot> {
+        let mut latest: Option<(u32, u32, Snapshot)> = None;
+        for slot in 0..SLOT_COUNT {
+            let mut record = [0u8; RECORD_SIZE];
+            if let Err(error) = self.flash.read(slot_offset(slot), &mut record) {
+                log::warn!("Failed to read journal slot {}: {:?}", slot, error);
+                continue;
+            }
+            match (Snapshot::decode(&record), latest) {
+                (Some((sequence, _)), Some((latest_sequence, _, _)))
+                    if !is_newer(sequence, latest_sequence) => {}
+                (Some((sequence, snapshot)), _) => latest = Some((sequence, slot, snapshot)),
+                (None, _) => {}
+            }
+        }
+
+        let (sequence, slot, snapshot) = latest?;
+        self.sequence = sequence;
+        self.next_slot = self.next_blank_slot((slot + 1) % SLOT_COUNT);
+        self.last = Some(snapshot);
+        Some(snapshot)
+    }
+
+    // A record torn by the reset leaves its slot written but not valid, and
+    // writing over it would tear the next record too. Skips to a blank slot,
+    // or to the start of the next sector, which is erased before writing.
+    fn next_blank_slot(&mut self, mut slot: u32) -> u32 {
+        while slot_offset(slot) % SECTOR_SIZE != 0 && !self.is_blank(slot) {
+            slot = (slot + 1) % SLOT_COUNT;
+        }
+        slot
+    }
+
+    fn is_blank(&mut self, slot: u32) -> bool {
+        let mut record = [0u8; RECORD_SIZE];
+        self.flash.read(slot_offset(slot), &mut record).is_ok()
+            && record.iter().all(|&byte| byte == 0xff)
+    }
+
+    // Failing to persist is logged but never stops the MMU.
+    fn write(&mut self, snapshot: Snapshot) {
+        if self.last == Some(snapshot) {
+            return;
+        }
+
+        let offset = slot_offset(self.next_slot);
+        if offset % SECTOR_SIZE == 0 {
+            if let Err(error) = self.flash.erase(offset, offset + SECTOR_SIZE) {
+                log::warn!(
+                    "Failed to erase journal sector at {:#x}: {:?}",
+                    offset,
+                    error
+                );
+                return;
+            }
+        }
+
+        self.sequence = self.sequence.wrapping_add(1);
+        if let Err(error) = self.flash.write(offset, &snapshot.encode(self.sequence)) {
+            log::warn!(
+                "Failed to write journal record at {:#x}: {:?}",
+                offset,
+                error
+            );
+            // the slot may be partly written, the next attempt uses another
+            self.next_slot = self.next_blank_slot((self.next_slot + 1) % SLOT_COUNT);
+            return;
+        }
+
+        self.next_slot = (self.next_slot + 1) % SLOT_COUNT;
+        self.last = Some(snapshot);
+    }
+}
+
+// Queues `snapshot` for `run_journal`.
+pub fn record(snapshot: Snapshot) {
+    PENDING.signal(snapshot);
+}
+
+pub async fn run_journal(mut journal: Journal) {
+    loop {
+        let snapshot = PENDING.wait().await;
+        journal.write(snapshot);
+    }
+}
+
+fn slot_offset(slot: u32) -> u32 {
+    JOURNAL_OFFSET + slot * RECORD_SIZE as u32
+}
+
+// Whether `sequence` was written after `than`, also across the wrap of the
+// counter.
+pub(crate) fn is_newer(sequence: u32, than: u32) -> bool {
+    (sequence.wrapping_sub(than) as i32) > 0
+}
+
+// CRC-32 (IEEE), bitwise: records are tiny and written rarely.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
@@ -0,0 +1,360 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Timing model of every MMU operation, computed from the same `Config` the
+// firmware moves with. `change`/`home` give the exact duration of an
+// operation from a known state, the `worst_case_*` variants the longest it
+// can take from any state.
+
+use embassy_time::Duration;
+
+use crate::{
+    config::{Backlash, Config, CutSequence, StepRamp},
+    cutter::CutterKind,
+    toolchange::ToolchangeKind,
+    units::{Millimeters, MmPerMin, StepInterval, Steps},
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChangeTiming {
+    // Cutting the loaded filament, or forming its tip.
+    pub tip: Duration,
+    pub move_to_current: Duration,
+    pub unload: Duration,
+    // Homing the selector again before selecting, when it is due.
+    pub rehome: Duration,
+    pub select: Duration,
+    pub load: Duration,
+    pub park: Duration,
+}
+
+impl ChangeTiming {
+    // From the start of the change until the previous filament is out.
+    pub fn unload_phase(&self) -> Duration {
+        self.tip + self.move_to_current + self.unload
+    }
+
+    // From the end of the unload until the new filament is loaded.
+    pub fn load_phase(&self) -> Duration {
+        self.rehome + self.select + self.load
+    }
+
+    pub fn total(&self) -> Duration {
+        self.unload_phase() + self.load_phase() + self.park
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HomeTiming {
+    pub unload: ChangeTiming,
+    pub cutter_settle: Duration,
+    pub homing_move: Duration,
+}
+
+impl HomeTiming {
+    pub fn total(&self) -> Duration {
+        self.unload.total() + self.cutter_settle + self.homing_move
+    }
+}
+
+// Every step is a high and a low phase, each lasting `step_speed`.
+pub fn steps(steps: Steps, step_speed: StepInterval) -> Duration {
+    step_speed.0 * 2 * steps.0
+}
+
+// A ramped move takes longer by the slower steps at either end.
+pub fn ramped_steps(move_steps: Steps, step_speed: StepInterval, ramp: &StepRamp) -> Duration {
+    let head = ramp.steps.0.min(move_steps.0);
+    let tail = move_steps.0.saturating_sub(ramp.steps.0).max(head);
+    let cruise = steps(Steps(tail - head), step_speed);
+    (0..head)
+        .chain(tail..move_steps.0)
+        .map(|step| ramp.interval(step, move_steps, step_speed).0 * 2)
+        .fold(cruise, |total, step| total + step)
+}
+
+// The servo waits `ramp_interval` between ramp steps, not after the last.
+pub fn servo_move(config: &Config, from: f32, to: f32) -> Duration {
+    if config.cutter != CutterKind::Servo {
+        return Duration::from_ticks(0);
+    }
+    config.servo.ramp_interval * config.servo.ramp_steps(from, to).saturating_sub(1)
+}
+
+pub fn open_cutter(config: &Config) -> Duration {
+    if config.cutter == CutterKind::Servo && config.servo.detach {
+        config.servo.detach_delay
+    } else {
+        Duration::from_ticks(0)
+    }
+}
+
+// Only the servo needs time to come to rest after opening.
+pub fn settle_cutter(config: &Config) -> Duration {
+    if config.cutter == CutterKind::Servo {
+        config.servo_settle_time
+    } else {
+        Duration::from_ticks(0)
+    }
+}
+
+pub fn cut(config: &Config, sequence: &CutSequence) -> Duration {
+    let rest = config.servo_resting_angle;
+    let gaps = sequence.strokes.len().saturating_sub(1) as u32;
+    let wiggle = steps(sequence.wiggle_steps, config.extruder_step_speed) * 2;
+    let strokes = sequence
+        .strokes
+        .iter()
+        .map(|stroke| {
+            servo_move(config, rest, stroke.cutting_angle)
+                + stroke.dwell
+                + servo_move(config, stroke.cutting_angle, rest)
+        })
+        .fold(Duration::from_ticks(0), |total, stroke| total + stroke);
+    strokes + (sequence.release_dwell + wiggle) * gaps + open_cutter(config)
+}
+
+// An invalid move is refused by the firmware, and takes no time.
+pub fn extruder_move(config: &Config, mm: Millimeters, speed: MmPerMin) -> Duration {
+    match config.extruder_move(mm.abs(), speed) {
+        Ok((move_steps, interval)) => steps(move_steps, interval),
+        Err(_) => Duration::from_ticks(0),
+    }
+}
+
+pub fn tip_forming(config: &Config) -> Duration {
+    let sequence = &config.tip_forming;
+    let ramming = sequence
+        .ramming
+        .iter()
+        .map(|tip_move| extruder_move(config, tip_move.mm, tip_move.mm_per_min))
+        .fold(Duration::from_ticks(0), |total, duration| total + duration);
+    let cooling = extruder_move(config, sequence.cooling_mm, sequence.cooling_mm_per_min)
+        * 2
+        * sequence.cooling_moves;
+    ramming + cooling
+}
+
+pub fn worst_case_cut(config: &Config) -> Duration {
+    (0..config.lane_geometry.len())
+        .map(|filament| {
+            let sequence = config.cut_sequence(filament);
+            cut(config, sequence) * (1 + sequence.retries as u32)
+        })
+        .max()
+        .unwrap_or_default()
+}
+
+// A cut that is retried unloads again every time.
+fn unload_attempts(config: &Config, filament: usize) -> Duration {
+    let attempts = match config.toolchange {
+        ToolchangeKind::Cut => 1 + config.cut_sequence(filament).retries as u32,
+        ToolchangeKind::TipForming => 1,
+    };
+    unload(config, filament) * attempts
+}
+
+// Backlash compensation depends on the previous move, so it is always
+// counted and this is the longest the move can take.
+pub fn selector_move(config: &Config, from: Steps, to: Steps) -> Duration {
+    let extra = match config.selector_backlash {
+        _ if from == to => Steps(0),
+        Backlash::None => Steps(0),
+        Backlash::Compensate(slack) => slack,
+        Backlash::FinalApproach(overshoot) if to < from => {
+            let overshoot = config.final_approach_overshoot(to, overshoot);
+            overshoot + overshoot
+        }
+        Backlash::FinalApproach(_) => Steps(0),
+    };
+    steps(from.abs_diff(to) + extra, config.selector_step_speed)
+}
+
+pub fn unload(config: &Config, filament: usize) -> Duration {
+    let profile = config.profile(filament);
+    ramped_steps(
+        config.unload_steps_for(filament),
+        profile.unload_step_speed,
+        &profile.ramp,
+    )
+}
+
+pub fn fast_load(config: &Config, filament: usize) -> Duration {
+    let profile = config.profile(filament);
+    ramped_steps(
+        config.fast_load_steps_for(filament),
+        profile.fast_load_step_speed,
+        &profile.ramp,
+    )
+}
+
+pub fn slow_load(config: &Config, filament: usize) -> Duration {
+    let profile = config.profile(filament);
+    ramped_steps(
+        config.slow_load_steps_for(filament),
+        profile.slow_load_step_speed,
+        &profile.ramp,
+    )
+}
+
+pub fn load(config: &Config, filament: usize) -> Duration {
+    fast_load(config, filament) + slow_load(config, filament)
+}
+
+// Lanes can have different bowden lengths and materials, so the printer
+// waits for the longest. The profile is generated from the materials set at
+// boot.
+fn worst_lane(config: &Config, timing: fn(&Config, usize) -> Duration) -> Duration {
+    (0..config.lane_geometry.len())
+        .map(|filament| timing(config, filament))
+        .max()
+        .unwrap_or_default()
+}
+
+// Selection is padded to always take as long as the longest move to any
+// lane, from home or another lane, so the printer can wait a fixed time for
+// it. A final approach makes moves towards home the longest.
+pub fn equalized_selection(config: &Config) -> Duration {
+    let positions = config.lane_geometry.map(|lane| lane.selector_position);
+    core::iter::once(Steps(0))
+        .chain(positions)
+        .flat_map(|from| positions.map(|to| selector_move(config, from, to)))
+        .max()
+        .unwrap_or_default()
+}
+
+// Homing to the selector endstop covers at most `homing_steps` at full
+// homing speed, so this is its longest too.
+pub fn homing_move(config: &Config) -> Duration {
+    let homing_steps_half = config.homing_steps.half();
+    steps(homing_steps_half, config.homing_step_speed)
+        + steps(homing_steps_half, config.homing_step_speed * 2)
+}
+
+// Exact timing of `change_filament` with the selector at `position` and
+// `from` currently loaded, homing again before selecting if `rehome` and
+// parking for `next` after it.
+pub fn change(
+    config: &Config,
+    position: Steps,
+    from: Option<usize>,
+    to: Option<usize>,
+    rehome: bool,
+    next: Option<usize>,
+) -> ChangeTiming {
+    let mut timing = ChangeTiming::default();
+    if from == to {
+        return timing;
+    }
+
+    let mut position = position;
+    if let Some(from) = from {
+        let target = config.lane_position(from);
+        timing.move_to_current = selector_move(config, position, target);
+        timing.tip = match config.toolchange {
+            ToolchangeKind::Cut => cut(config, config.cut_sequence(from)),
+            ToolchangeKind::TipForming => {
+                // padded, see TipFormingStrategy
+                timing.move_to_current = worst_move_to_current(config);
+                tip_forming(config)
+            }
+        };
+        timing.unload = unload(config, from);
+        position = target;
+    }
+
+    if let Some(to) = to {
+        if rehome {
+            timing.rehome = homing_move(config);
+            position = Steps(0);
+        }
+        let target = config.lane_position(to);
+        timing.select = selector_move(config, position, target).max(equalized_selection(config));
+        timing.load = load(config, to);
+        timing.park = selector_move(config, target, config.park_position(to, next));
+    }
+    timing
+}
+
+// The selector always starts a change parked for the loaded filament, so
+// the longest way back is the largest park offset, for any guess of the
+// next lane.
+pub fn worst_move_to_current(config: &Config) -> Duration {
+    let lanes = 0..config.lane_geometry.len();
+    lanes
+        .clone()
+        .flat_map(|filament| lanes.clone().map(move |next| (filament, next)))
+        .map(|(filament, next)| {
+            selector_move(
+                config,
+                config.park_position(filament, Some(next)),
+                config.lane_position(filament),
+            )
+        })
+        .max()
+        .unwrap_or_default()
+}
+
+pub fn worst_case_change(config: &Config) -> ChangeTiming {
+    let move_to_current = worst_move_to_current(config);
+    let tip = match config.toolchange {
+        ToolchangeKind::Cut => worst_case_cut(config),
+        ToolchangeKind::TipForming => tip_forming(config),
+    };
+
+    // Any change can be the one that homes again.
+    let rehome = if config.rehome_after_changes.is_some() || config.rehome_when_idle.is_some() {
+        homing_move(config)
+    } else {
+        Duration::from_ticks(0)
+    };
+
+    ChangeTiming {
+        tip,
+        move_to_current,
+        unload: worst_lane(config, unload_attempts),
+        rehome,
+        select: equalized_selection(config),
+        load: worst_lane(config, load),
+        park: move_to_current,
+    }
+}
+
+// Exact timing of `home` with the selector at `position` and `filament`
+// currently loaded.
+pub fn home(config: &Config, position: Steps, filament: Option<usize>) -> HomeTiming {
+    HomeTiming {
+        unload: change(config, position, filament, None, false, None),
+        cutter_settle: open_cutter(config) + settle_cutter(config),
+        homing_move: homing_move(config),
+    }
+}
+
+pub fn worst_case_home(config: &Config) -> HomeTiming {
+    let worst = worst_case_change(config);
+    HomeTiming {
+        unload: ChangeTiming {
+            tip: worst.tip,
+            move_to_current: worst.move_to_current,
+            unload: worst.unload,
+            ..ChangeTiming::default()
+        },
+        cutter_settle: open_cutter(config) + settle_cutter(config),
+        homing_move: homing_move(config),
+    }
+}
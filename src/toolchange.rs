@@ -0,0 +1,149 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// How the loaded filament is taken out of the printer before the next one
+// is selected: cut at the hub, or a tip formed in the hotend for printers
+// without room for a cutter.
+
+use embassy_time::{Duration, Instant};
+
+use crate::{
+    config::Config,
+    error::Error,
+    events::{self, Event},
+    timing,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ToolchangeKind {
+    Cut,
+    TipForming,
+}
+
+impl ToolchangeKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            ToolchangeKind::Cut => "cut",
+            ToolchangeKind::TipForming => "tip",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [ToolchangeKind::Cut, ToolchangeKind::TipForming]
+            .into_iter()
+            .find(|kind| kind.name().eq_ignore_ascii_case(name))
+    }
+}
+
+// What a strategy can ask of the filament changer. The lane states and the
+// selector checks stay inside the changer, whatever the strategy does.
+pub trait ToolchangeContext {
+    fn config(&self) -> &Config;
+    // Moves the selector onto the lane of `filament`.
+    async fn pick_up(&mut self, filament: usize) -> Result<(), Error>;
+    // Cuts `filament`, which leaves its lane in the hub.
+    async fn cut(&mut self, filament: usize) -> Result<(), Error>;
+    async fn form_tip(&mut self, filament: usize) -> Result<(), Error>;
+    // Pulls `filament` back behind the selector.
+    async fn unload(&mut self, filament: usize) -> Result<(), Error>;
+    // Pads whatever started at `start` to take `duration`.
+    async fn wait_until(&mut self, start: Instant, duration: Duration) -> Result<(), Error>;
+}
+
+pub trait ToolchangeStrategy {
+    // Takes `filament` out of the printer and parks it behind the selector.
+    async fn unload(
+        &self,
+        changer: &mut impl ToolchangeContext,
+        filament: usize,
+    ) -> Result<(), Error>;
+}
+
+pub struct CutStrategy;
+
+impl ToolchangeStrategy for CutStrategy {
+    async fn unload(
+        &self,
+        changer: &mut impl ToolchangeContext,
+        filament: usize,
+    ) -> Result<(), Error> {
+        // Wiggling needs the extruder to grip the filament, so the
+        // selector picks it up before cutting instead of after.
+        let sequence = *changer.config().cut_sequence(filament);
+        let mut picked_up = sequence.wiggle_steps.0 > 0;
+        if picked_up {
+            changer.pick_up(filament).await?;
+        }
+        // A filament still at the hub after unloading wasn't cut through.
+        let mut attempt = 0;
+        loop {
+            changer.cut(filament).await?;
+            events::publish(Event::CutDone { filament });
+            if !picked_up {
+                changer.pick_up(filament).await?;
+                picked_up = true;
+            }
+            match changer.unload(filament).await {
+                Err(Error::HubOccupied) if attempt < sequence.retries => {
+                    attempt += 1;
+                    log::warn!(
+                        "Filament {} not cut through, retrying {}/{}",
+                        filament,
+                        attempt,
+                        sequence.retries
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+pub struct TipFormingStrategy;
+
+impl ToolchangeStrategy for TipFormingStrategy {
+    async fn unload(
+        &self,
+        changer: &mut impl ToolchangeContext,
+        filament: usize,
+    ) -> Result<(), Error> {
+        // The printer moves its extruder along with ours, so the tip
+        // forming has to start after a fixed time.
+        let start_time = Instant::now();
+        changer.pick_up(filament).await?;
+        let pick_up = timing::worst_move_to_current(changer.config());
+        changer.wait_until(start_time, pick_up).await?;
+
+        changer.form_tip(filament).await?;
+        events::publish(Event::TipFormed { filament });
+        changer.unload(filament).await
+    }
+}
+
+impl ToolchangeStrategy for ToolchangeKind {
+    async fn unload(
+        &self,
+        changer: &mut impl ToolchangeContext,
+        filament: usize,
+    ) -> Result<(), Error> {
+        match self {
+            ToolchangeKind::Cut => CutStrategy.unload(changer, filament).await,
+            ToolchangeKind::TipForming => TipFormingStrategy.unload(changer, filament).await,
+        }
+    }
+}
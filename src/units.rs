@@ -0,0 +1,165 @@
+/*
+generic-mmu
+Copyright (C) 2024  eberlitz`
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+// Newtypes for the quantities the MMU moves by, so a step count can't be
+// passed where millimetres are expected. Steps and millimetres only convert
+// through the steps/mm of an axis.
+
+use core::{
+    fmt,
+    ops::{Add, Mul, Neg, Sub},
+};
+
+use embassy_time::Duration;
+
+// Motor steps, a distance or a count. Selector positions are the steps from
+// home.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Steps(pub u32);
+
+impl Steps {
+    pub fn half(self) -> Steps {
+        Steps(self.0 / 2)
+    }
+
+    pub fn abs_diff(self, other: Steps) -> Steps {
+        Steps(self.0.abs_diff(other.0))
+    }
+
+    pub fn saturating_add(self, other: Steps) -> Steps {
+        Steps(self.0.saturating_add(other.0))
+    }
+
+    pub fn saturating_sub(self, other: Steps) -> Steps {
+        Steps(self.0.saturating_sub(other.0))
+    }
+
+    pub fn checked_add_signed(self, steps: i32) -> Option<Steps> {
+        self.0.checked_add_signed(steps).map(Steps)
+    }
+}
+
+impl Add for Steps {
+    type Output = Steps;
+
+    fn add(self, other: Steps) -> Steps {
+        Steps(self.0 + other.0)
+    }
+}
+
+impl Sub for Steps {
+    type Output = Steps;
+
+    fn sub(self, other: Steps) -> Steps {
+        Steps(self.0 - other.0)
+    }
+}
+
+impl fmt::Display for Steps {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Filament length. Negative only for a move away from the nozzle.
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct Millimeters(pub f32);
+
+impl Millimeters {
+    pub fn abs(self) -> Millimeters {
+        Millimeters(self.0.abs())
+    }
+}
+
+impl Neg for Millimeters {
+    type Output = Millimeters;
+
+    fn neg(self) -> Millimeters {
+        Millimeters(-self.0)
+    }
+}
+
+// Feedrate, as in G-code.
+#[derive(Clone, Copy, Debug, Default, PartialEq, PartialOrd)]
+pub struct MmPerMin(pub f32);
+
+// How long the step pin stays high, and then low, for every step.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StepInterval(pub Duration);
+
+impl StepInterval {
+    pub const fn from_micros(micros: u64) -> StepInterval {
+        StepInterval(Duration::from_micros(micros))
+    }
+}
+
+// Stretches the interval, to move `factor` times slower.
+impl Mul<u32> for StepInterval {
+    type Output = StepInterval;
+
+    fn mul(self, factor: u32) -> StepInterval {
+        StepInterval(self.0 * factor)
+    }
+}
+
+// Servo pulse width in µs, which is also the MCPWM timestamp.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ServoPulse(pub u16);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StepsPerMm(pub f32);
+
+// Conversions run in fixed point, so they give the same result for the same
+// inputs on the MMU and in the slicer profile: lengths and feedrates in
+// thousandths of a mm, steps/mm in thousandths of a step.
+const MILLI: f32 = 1_000.0;
+const MICROS_PER_MINUTE: u64 = 60_000_000;
+
+fn to_milli(value: f32) -> u64 {
+    // saturates, and NaN is 0
+    (value * MILLI).round() as u64
+}
+
+impl StepsPerMm {
+    // None for a negative length, or one too long to count in steps.
+    pub fn steps(self, mm: Millimeters) -> Option<Steps> {
+        if mm.0.is_nan() || mm.0 < 0.0 {
+            return None;
+        }
+        let steps = to_milli(mm.0).checked_mul(to_milli(self.0))? / 1_000_000;
+        u32::try_from(steps).ok().map(Steps)
+    }
+
+    pub fn mm(self, steps: Steps) -> Millimeters {
+        Millimeters(steps.0 as f32 / self.0)
+    }
+
+    // The interval that steps at `speed`: half of a step's period, since the
+    // pin stays high and then low for it. Rounded to the nearest µs. None
+    // for a feedrate that isn't positive or is too fast to step.
+    pub fn interval(self, speed: MmPerMin) -> Option<StepInterval> {
+        // steps per minute, times 1_000_000
+        let steps_per_minute = to_milli(speed.0).checked_mul(to_milli(self.0))?;
+        if steps_per_minute == 0 {
+            return None;
+        }
+        let half_steps = steps_per_minute.checked_mul(2)?;
+        let micros = (MICROS_PER_MINUTE * 1_000_000 + steps_per_minute) / half_steps;
+        (micros > 0).then_some(StepInterval::from_micros(micros))
+    }
+}